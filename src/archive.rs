@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path};
+
+/// Compression used for a cache archive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Zstd,
+    Gzip,
+}
+
+/// A file stored in an archive, as recorded in the entry's manifest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchivedFile {
+    pub path: String,
+    pub size: u64,
+    /// Unix permission bits.
+    pub mode: u32,
+    /// BLAKE3 hash of the file's contents.
+    pub hash: String,
+}
+
+/// Write files, relative to `dir`, into a compressed tar archive at `archive`,
+/// returning what was written.
+pub fn write(
+    archive: &Path,
+    compression: Compression,
+    dir: &Path,
+    files: &[String],
+) -> io::Result<Vec<ArchivedFile>> {
+    let writer = BufWriter::new(File::create(archive)?);
+    let (mut writer, archived) = match compression {
+        Compression::Zstd => {
+            let (encoder, archived) = write_tar(zstd::Encoder::new(writer, 0)?, dir, files)?;
+            (encoder.finish()?, archived)
+        }
+        Compression::Gzip => {
+            let encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::default());
+            let (encoder, archived) = write_tar(encoder, dir, files)?;
+            (encoder.finish()?, archived)
+        }
+    };
+    writer.flush()?;
+    Ok(archived)
+}
+
+/// Write the tar stream, returning the underlying writer so the compression
+/// can be finished.
+fn write_tar<W: Write>(
+    writer: W,
+    dir: &Path,
+    files: &[String],
+) -> io::Result<(W, Vec<ArchivedFile>)> {
+    let mut builder = tar::Builder::new(writer);
+    builder.mode(tar::HeaderMode::Deterministic);
+
+    let mut archived = Vec::new();
+    for file in files {
+        let path = dir.join(file);
+        let metadata = fs::metadata(&path)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(metadata.len());
+        header.set_mode(mode(&metadata));
+        let mut reader = HashingReader::new(File::open(&path)?);
+        builder.append_data(&mut header, file, &mut reader)?;
+
+        archived.push(ArchivedFile {
+            path: file.clone(),
+            size: metadata.len(),
+            mode: mode(&metadata),
+            hash: reader.hash(),
+        });
+    }
+    Ok((builder.into_inner()?, archived))
+}
+
+/// Extract an archive into `dir`. Only the files listed in `files` are
+/// extracted, and each one must match its recorded hash.
+pub fn extract(
+    archive: &Path,
+    compression: Compression,
+    dir: &Path,
+    files: &[ArchivedFile],
+) -> io::Result<()> {
+    let reader = BufReader::new(File::open(archive)?);
+    let decoder: Box<dyn Read> = match compression {
+        Compression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        Compression::Gzip => Box::new(flate2::read::GzDecoder::new(reader)),
+    };
+    let mut expected = files
+        .iter()
+        .map(|file| (file.path.as_str(), file))
+        .collect::<HashMap<_, _>>();
+
+    let mut archive = tar::Archive::new(decoder);
+    fs::create_dir_all(dir)?;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().replace('\\', "/");
+        let Some(file) = expected.remove(path.as_str()) else {
+            return Err(invalid(format!("{path:?} isn't listed in the manifest")));
+        };
+        if !is_inside(&file.path) {
+            return Err(invalid(format!("{path:?} is outside the package")));
+        }
+
+        let dest = dir.join(&file.path);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Never write into an existing file, it could be a hardlink into
+        // another cache entry.
+        if let Err(err) = fs::remove_file(&dest) {
+            if err.kind() != io::ErrorKind::NotFound {
+                return Err(err);
+            }
+        }
+        let mut reader = HashingReader::new(&mut entry);
+        io::copy(&mut reader, &mut File::create(&dest)?)?;
+        if reader.hash() != file.hash {
+            return Err(invalid(format!("{path:?} doesn't match its hash")));
+        }
+        set_mode(&dest, file.mode)?;
+    }
+
+    match expected.keys().next() {
+        Some(path) => Err(invalid(format!("{path:?} is missing from the archive"))),
+        None => Ok(()),
+    }
+}
+
+/// Write every file inside `dir` into a zstd compressed tar archive, used to
+/// share a whole cache entry as a single file.
+pub fn pack_dir(dir: &Path, archive: &Path) -> io::Result<()> {
+    let writer = BufWriter::new(File::create(archive)?);
+    let mut builder = tar::Builder::new(zstd::Encoder::new(writer, 0)?);
+    builder.mode(tar::HeaderMode::Deterministic);
+    builder.append_dir_all(".", dir)?;
+    builder.into_inner()?.finish()?.flush()
+}
+
+/// Extract an archive written by `pack_dir` into `dir`. Entries that would end
+/// up outside of `dir` are skipped. `pack_dir` follows symlinks, so archives
+/// with anything but files and directories are rejected.
+pub fn unpack_dir(archive: &Path, dir: &Path) -> io::Result<()> {
+    let decoder = zstd::Decoder::new(BufReader::new(File::open(archive)?))?;
+    let mut archive = tar::Archive::new(decoder);
+    fs::create_dir_all(dir)?;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let kind = entry.header().entry_type();
+        if !kind.is_file() && !kind.is_dir() {
+            let path = entry.path()?.to_string_lossy().to_string();
+            return Err(invalid(format!("{path:?} isn't a file or directory")));
+        }
+        entry.unpack_in(dir)?;
+    }
+    Ok(())
+}
+
+/// Whether a relative path, like one listed in a manifest, stays inside the
+/// directory it's joined onto.
+pub fn is_inside(path: &str) -> bool {
+    let mut components = Path::new(path).components().peekable();
+    components.peek().is_some()
+        && components.all(|component| matches!(component, Component::Normal(_)))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Hashes everything read through it.
+struct HashingReader<R> {
+    inner: R,
+    hasher: blake3::Hasher,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            hasher: blake3::Hasher::new(),
+        }
+    }
+
+    fn hash(self) -> String {
+        self.hasher.finalize().to_hex().to_string()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(unix)]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o200 == 0);
+    fs::set_permissions(path, permissions)
+}
@@ -0,0 +1,1113 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use glob::Pattern;
+
+use crate::archive::{self, ArchivedFile, Compression};
+use crate::colors::{DIM, RESET};
+use crate::ctx::Ctx;
+use crate::globby::globby;
+use crate::hash::hash_file;
+use crate::remote::CacheBackend;
+
+/// Directory inside a cache entry holding the cached build outputs, laid out
+/// the same way they are inside the package directory. Archived entries store
+/// the same layout in `outputs.<format>` instead.
+const OUTPUT_DIR: &str = "outputs";
+/// File written last when populating a cache entry. Entries without it are
+/// incomplete and are never restored.
+const MANIFEST_FILE: &str = "manifest.json";
+/// File recording the inputs a cache entry's hash was computed from, used to
+/// explain cache misses.
+const INPUTS_FILE: &str = "inputs.json";
+/// Version of the cache entry format and hashing scheme. It's part of every
+/// package hash and recorded in each manifest, so bumping it invalidates all
+/// existing entries.
+pub const CACHE_VERSION: u32 = 2;
+/// Marker in the name of directories that are still being populated.
+const TEMP_MARKER: &str = ".tmp-";
+/// File in the cache directory holding the key environment variable values
+/// are hashed with, see `inputs_key`.
+const INPUTS_KEY_FILE: &str = ".inputs-key";
+
+/// Returns true when a cache entry was fully written and can be restored.
+/// Entries written by older versions of buildc, with a different layout, are
+/// treated as incomplete.
+pub fn is_complete(entry_dir: &Path) -> bool {
+    read_manifest(entry_dir).is_ok_and(|manifest| entry_dir.join(manifest.format.data()).exists())
+}
+
+/// Delete cache entries that were never completed, like ones left behind by a
+/// crashed or killed process, and entries from older cache versions. Must be
+/// called while holding the package's lock.
+pub fn clean_incomplete(ctx: &Ctx, package_cache_dir: &Path) {
+    let Ok(entries) = fs::read_dir(package_cache_dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let is_temp = entry.file_name().to_string_lossy().contains(TEMP_MARKER);
+        if path.is_dir() && (is_temp || !is_complete(&path)) {
+            if ctx.is_debug {
+                println!(
+                    "{DIM}[buildc] → Removing incomplete or outdated cache entry {path:?}{RESET}"
+                );
+            }
+            let _ = fs::remove_dir_all(&path);
+        } else if is_temp {
+            // Artifacts downloaded from or uploaded to a remote cache.
+            let _ = fs::remove_file(&path);
+        }
+    }
+}
+
+/// Delete entries stored directly in the package's cache directory, at
+/// `.cache/<package>/<hash>`, by versions of buildc from before each script was
+/// cached separately. They're never restored, so they'd only take up space.
+/// Must be called while holding the package's lock.
+pub fn clean_old_layout(ctx: &Ctx, package_cache_dir: &Path) {
+    let Ok(entries) = fs::read_dir(package_cache_dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Old entries are named after an MD5 or BLAKE3 hash, script
+        // directories after package.json scripts.
+        let is_hash = name.len() >= 32 && name.chars().all(|c| c.is_ascii_hexdigit());
+        if is_hash && entry.path().is_dir() {
+            if ctx.is_debug {
+                println!(
+                    "{DIM}[buildc] → Removing cache entry with an old layout {:?}{RESET}",
+                    entry.path()
+                );
+            }
+            let _ = fs::remove_dir_all(entry.path());
+        }
+    }
+}
+
+/// How files are copied between the cache and the package directory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestoreStrategy {
+    /// Always make a full copy.
+    Copy,
+    /// Hardlink files when restoring, falling back to a copy when the cache is
+    /// on a different file system. An edit to a restored file is also an edit
+    /// to the cache entry, so on Unix linked files are made read-only, and
+    /// files are checked against their hash before they're linked.
+    Hardlink,
+    /// Clone files with copy-on-write, falling back to a copy when the file
+    /// system doesn't support it.
+    Reflink,
+    /// The default, same as `Reflink`. Hardlinks are never used unless asked
+    /// for.
+    Auto,
+}
+
+impl RestoreStrategy {
+    pub const ALL: [RestoreStrategy; 4] = [Self::Copy, Self::Hardlink, Self::Reflink, Self::Auto];
+
+    /// The value used for this strategy in the config.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Copy => "copy",
+            Self::Hardlink => "hardlink",
+            Self::Reflink => "reflink",
+            Self::Auto => "auto",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<RestoreStrategy> {
+        Self::ALL
+            .into_iter()
+            .find(|strategy| strategy.name() == name)
+    }
+
+    /// The strategy used to copy outputs into a new entry. Saving never
+    /// hardlinks: the build's own files would share their contents with the
+    /// entry, so the next build writing an output in place would change it.
+    fn for_save(&self) -> RestoreStrategy {
+        match self {
+            Self::Copy => Self::Copy,
+            _ => Self::Reflink,
+        }
+    }
+
+    /// Copy or link a single file. The destination is always removed first:
+    /// writing into it could write through a hardlink into a cache entry.
+    fn transfer(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if let Err(err) = fs::remove_file(to) {
+            if err.kind() != io::ErrorKind::NotFound {
+                return Err(err);
+            }
+        }
+        let copy = || fs::copy(from, to).map(|_| ());
+        match self {
+            Self::Copy => copy(),
+            Self::Hardlink => {
+                set_writable(from, false)?;
+                fs::hard_link(from, to).or_else(|_| copy().and_then(|_| set_writable(to, true)))
+            }
+            Self::Reflink | Self::Auto => reflink_copy::reflink_or_copy(from, to).map(|_| ()),
+        }
+    }
+}
+
+/// How a cache entry stores its outputs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheFormat {
+    /// A plain copy of the output files, which can be restored with any
+    /// `RestoreStrategy`.
+    Directory,
+    /// A single zstd compressed tar file.
+    TarZstd,
+    /// A single gzip compressed tar file.
+    TarGzip,
+}
+
+impl CacheFormat {
+    pub const ALL: [CacheFormat; 3] = [Self::Directory, Self::TarZstd, Self::TarGzip];
+
+    /// The value used for this format in the config and manifest.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Directory => "directory",
+            Self::TarZstd => "tar.zst",
+            Self::TarGzip => "tar.gz",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<CacheFormat> {
+        Self::ALL.into_iter().find(|format| format.name() == name)
+    }
+
+    fn compression(&self) -> Option<Compression> {
+        match self {
+            Self::Directory => None,
+            Self::TarZstd => Some(Compression::Zstd),
+            Self::TarGzip => Some(Compression::Gzip),
+        }
+    }
+
+    /// Name of the directory or archive holding the outputs inside an entry.
+    fn data(&self) -> String {
+        match self {
+            Self::Directory => OUTPUT_DIR.to_string(),
+            _ => format!("{OUTPUT_DIR}.{}", self.name()),
+        }
+    }
+}
+
+/// Copy the cached outputs back into the package directory, returning how long
+/// the build that created the entry took. Entries for scripts without outputs
+/// have nothing to restore.
+///
+/// Every cached file is checked against the manifest. Hardlinked files share
+/// their contents with the cache, so a tool editing a restored file in place
+/// also edits the entry. When that happens, an error is returned instead of
+/// restoring bad output, and the caller discards the entry and rebuilds like
+/// on a cache miss. Archived files are checked against their hash while
+/// they're extracted.
+pub fn restore(
+    ctx: &Ctx,
+    package_dir: &Path,
+    strategy: RestoreStrategy,
+    entry_dir: &Path,
+) -> io::Result<Option<Duration>> {
+    let manifest = read_manifest(entry_dir)?;
+    let data = entry_dir.join(manifest.format.data());
+    if ctx.is_debug {
+        println!(
+            "{DIM}[buildc] → Restoring {data:?} to {package_dir:?} ({}){RESET}",
+            match manifest.format {
+                CacheFormat::Directory => strategy.name(),
+                format => format.name(),
+            }
+        );
+    }
+
+    match manifest.files {
+        ManifestFiles::Directory(files) => {
+            for file in &files {
+                file.verify(&data, strategy == RestoreStrategy::Hardlink)?;
+            }
+            for file in &files {
+                transfer_file(strategy, &data, package_dir, &file.path)?;
+            }
+        }
+        ManifestFiles::Archive(files) => {
+            let compression = manifest.format.compression().unwrap();
+            archive::extract(&data, compression, package_dir, &files)?;
+        }
+    }
+    Ok(manifest.build_duration)
+}
+
+/// Copy the package's outputs into a new cache entry. The outputs are copied
+/// into a temporary sibling directory that is only renamed into place once the
+/// copy and manifest are written, so an interrupted copy never looks like a
+/// valid entry. Without outputs, the entry only records that the script
+/// succeeded.
+pub fn save(
+    ctx: &Ctx,
+    package_dir: &Path,
+    outputs: &[String],
+    strategy: RestoreStrategy,
+    format: CacheFormat,
+    build_duration: Duration,
+    entry_dir: &Path,
+) -> io::Result<()> {
+    if ctx.is_debug {
+        println!("{DIM}[buildc] → Caching {outputs:?} to {entry_dir:?}{RESET}");
+    }
+
+    let files = output_files(package_dir, outputs, false)?;
+
+    let temp_dir = temp_entry_dir(entry_dir);
+    write_entry(
+        package_dir,
+        &files,
+        strategy,
+        format,
+        build_duration,
+        &temp_dir,
+    )
+    .and_then(|_| move_into_place(&temp_dir, entry_dir))
+    .inspect_err(|_| {
+        let _ = fs::remove_dir_all(&temp_dir);
+    })
+}
+
+/// Rename a completed entry from its temporary directory into place, replacing
+/// any incomplete or outdated entry. When another writer, like a concurrent
+/// upload to `buildc cache-server`, completes the same entry first, theirs is
+/// kept: entries are keyed by hash, so both have the same contents.
+///
+/// The old entry is renamed aside before it's removed, so other writers never
+/// see it half deleted.
+fn move_into_place(temp_dir: &Path, entry_dir: &Path) -> io::Result<()> {
+    let old_dir = temp_entry_dir(entry_dir);
+    let result = match fs::rename(entry_dir, &old_dir) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => fs::rename(temp_dir, entry_dir),
+    };
+    let _ = fs::remove_dir_all(&old_dir);
+    match result {
+        Err(_) if is_complete(entry_dir) => {
+            let _ = fs::remove_dir_all(temp_dir);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Cache entries stored as directories at `<dir>/<key>`, see `CacheBackend`.
+/// This is the layout of the local `.cache` directory, which is also used by
+/// `"remoteCache": { "dir": "..." }` and served by `buildc cache-server`.
+/// Entries are exchanged with other backends as artifacts packed with
+/// `archive::pack_dir`.
+pub struct LocalCache {
+    dir: PathBuf,
+}
+
+impl LocalCache {
+    pub fn new(dir: PathBuf) -> LocalCache {
+        LocalCache { dir }
+    }
+
+    pub fn entry_dir(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+
+    /// Return a new path to temporarily store an entry's artifact at, next to
+    /// the entry. `clean_incomplete` deletes the ones that are left behind.
+    pub fn temp_artifact(&self, key: &str) -> io::Result<PathBuf> {
+        let entry_dir = self.entry_dir(key);
+        if let Some(parent) = entry_dir.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut path = temp_entry_dir(&entry_dir).into_os_string();
+        path.push(".tar.zst");
+        Ok(PathBuf::from(path))
+    }
+}
+
+impl CacheBackend for LocalCache {
+    fn location(&self) -> String {
+        self.dir.display().to_string()
+    }
+
+    fn has(&self, key: &str) -> io::Result<bool> {
+        Ok(is_complete(&self.entry_dir(key)))
+    }
+
+    fn get(&self, key: &str, dest: &Path) -> io::Result<bool> {
+        let entry_dir = self.entry_dir(key);
+        if !is_complete(&entry_dir) {
+            return Ok(false);
+        }
+        archive::pack_dir(&entry_dir, dest).map(|_| true)
+    }
+
+    /// Entries are keyed by hash, so a complete entry is never replaced.
+    fn put(&self, key: &str, artifact: &Path) -> io::Result<()> {
+        let entry_dir = self.entry_dir(key);
+        if is_complete(&entry_dir) {
+            return Ok(());
+        }
+        unpack(artifact, &entry_dir)
+    }
+}
+
+/// Download the entry with `key` from a remote cache into the local cache,
+/// returning whether the remote had it. Must be called while holding the
+/// package's lock.
+pub fn download(remote: &dyn CacheBackend, local: &LocalCache, key: &str) -> io::Result<bool> {
+    let artifact = local.temp_artifact(key)?;
+    let result = remote.get(key, &artifact).and_then(|found| match found {
+        true => local.put(key, &artifact).map(|_| true),
+        false => Ok(false),
+    });
+    let _ = fs::remove_file(&artifact);
+    result
+}
+
+/// Upload the entry with `key` from the local cache to a remote cache, unless
+/// the remote already has it.
+pub fn upload(remote: &dyn CacheBackend, local: &LocalCache, key: &str) -> io::Result<()> {
+    if remote.has(key)? {
+        return Ok(());
+    }
+    let artifact = local.temp_artifact(key)?;
+    let result = local.get(key, &artifact).and_then(|found| match found {
+        true => remote.put(key, &artifact),
+        false => Ok(()),
+    });
+    let _ = fs::remove_file(&artifact);
+    result
+}
+
+/// Unpack an entry shared through a cache backend. Like `save`, the entry is
+/// unpacked into a temporary directory that's renamed into place once it's
+/// complete.
+///
+/// Artifacts don't keep modification times, so the manifest of directory
+/// entries is updated with the unpacked files' times, after checking their
+/// sizes.
+fn unpack(artifact: &Path, entry_dir: &Path) -> io::Result<()> {
+    let temp_dir = temp_entry_dir(entry_dir);
+    let result = archive::unpack_dir(artifact, &temp_dir)
+        .and_then(|_| refresh_manifest(&temp_dir))
+        .and_then(|_| move_into_place(&temp_dir, entry_dir));
+    if result.is_err() {
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+    result
+}
+
+fn refresh_manifest(entry_dir: &Path) -> io::Result<()> {
+    let manifest = read_manifest(entry_dir)?;
+    let ManifestFiles::Directory(files) = manifest.files else {
+        return Ok(());
+    };
+    let data = entry_dir.join(manifest.format.data());
+    let mut json = serde_json::from_str::<serde_json::Value>(&fs::read_to_string(
+        entry_dir.join(MANIFEST_FILE),
+    )?)?;
+    let mut entries = Vec::new();
+    for file in files {
+        let entry = FileEntry::read(&data, &file.path)?;
+        if entry.size != file.size || entry.hash != file.hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} doesn't match the manifest", file.path),
+            ));
+        }
+        entries.push(entry.to_json());
+    }
+    json["files"] = entries.into();
+    fs::write(
+        entry_dir.join(MANIFEST_FILE),
+        serde_json::to_string_pretty(&json)?,
+    )
+}
+
+/// Return the secret key environment variable values are hashed with before
+/// they're saved in an entry's inputs. It's created the first time it's needed
+/// and never leaves the machine, so the saved hashes can't be used to guess
+/// short values, like tokens, from entries shared through a remote cache.
+pub fn inputs_key(cache_dir: &Path) -> io::Result<[u8; 32]> {
+    let path = cache_dir.join(INPUTS_KEY_FILE);
+    let read = |path: &Path| -> io::Result<[u8; 32]> {
+        fs::read(path)?
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid inputs key"))
+    };
+    if let Ok(key) = read(&path) {
+        return Ok(key);
+    }
+    let mut key = [0; 32];
+    getrandom::getrandom(&mut key).map_err(|err| io::Error::other(err.to_string()))?;
+    fs::create_dir_all(cache_dir)?;
+    // Linking fails when another process created the key first, its key is
+    // used instead.
+    let temp = cache_dir.join(format!(
+        "{INPUTS_KEY_FILE}{TEMP_MARKER}{}",
+        std::process::id()
+    ));
+    fs::write(&temp, key)?;
+    let linked = fs::hard_link(&temp, &path);
+    let _ = fs::remove_file(&temp);
+    match linked {
+        Ok(()) => Ok(key),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => read(&path),
+        Err(err) => Err(err),
+    }
+}
+
+/// Record the inputs an entry's hash was computed from, once it's saved.
+pub fn save_inputs(entry_dir: &Path, inputs: &serde_json::Value) -> io::Result<()> {
+    let temp = entry_dir.join(format!("{INPUTS_FILE}{TEMP_MARKER}{}", std::process::id()));
+    fs::write(&temp, serde_json::to_string_pretty(inputs)?)?;
+    fs::rename(&temp, entry_dir.join(INPUTS_FILE))
+}
+
+/// Return the complete entries in a script's cache directory that recorded
+/// their inputs, along with those inputs and when the entry was saved.
+pub fn entries_with_inputs(
+    script_cache_dir: &Path,
+) -> Vec<(PathBuf, serde_json::Value, SystemTime)> {
+    let Ok(entries) = fs::read_dir(script_cache_dir) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| is_complete(path))
+        .filter_map(|path| {
+            let content = fs::read_to_string(path.join(INPUTS_FILE)).ok()?;
+            let inputs = serde_json::from_str(&content).ok()?;
+            let saved_at = fs::metadata(path.join(MANIFEST_FILE))
+                .ok()?
+                .modified()
+                .ok()?;
+            Some((path, inputs, saved_at))
+        })
+        .collect()
+}
+
+/// Replace output files that are hardlinked into the cache with their own
+/// writable copy, so the build can't write through the link into a cache
+/// entry. Must be called before running a build.
+pub fn unlink_outputs(package_dir: &Path, outputs: &[String]) -> io::Result<()> {
+    for file in output_files(package_dir, outputs, true)? {
+        let path = package_dir.join(&file);
+        if !is_hardlinked(&fs::metadata(&path)?) {
+            continue;
+        }
+        let mut temp = path.clone().into_os_string();
+        temp.push(format!("{TEMP_MARKER}{}", std::process::id()));
+        fs::copy(&path, &temp)?;
+        set_writable(Path::new(&temp), true)?;
+        fs::rename(&temp, &path)?;
+    }
+    Ok(())
+}
+
+/// Make a file read-only, or writable again by its owner.
+#[cfg(unix)]
+fn set_writable(path: &Path, writable: bool) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path)?.permissions();
+    let mode = permissions.mode();
+    permissions.set_mode(if writable {
+        mode | 0o200
+    } else {
+        mode & !0o222
+    });
+    fs::set_permissions(path, permissions)
+}
+
+/// Hardlinks are never detected outside of Unix, see `is_hardlinked`, so
+/// restored files are left writable there.
+#[cfg(not(unix))]
+fn set_writable(_path: &Path, _writable: bool) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_hardlinked(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink() > 1
+}
+
+#[cfg(not(unix))]
+fn is_hardlinked(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+/// The contents of a cache entry's manifest.
+struct Manifest {
+    format: CacheFormat,
+    files: ManifestFiles,
+    /// How long the build that created the entry took.
+    build_duration: Option<Duration>,
+}
+
+/// The files listed in a manifest, which depend on the entry's format.
+enum ManifestFiles {
+    Directory(Vec<FileEntry>),
+    Archive(Vec<ArchivedFile>),
+}
+
+/// A file in a cache entry. The size and modification time are recorded so
+/// changes made to the entry after it was written can be detected, and the
+/// hash to detect changes that kept the same size and modification time.
+#[derive(Debug, PartialEq)]
+struct FileEntry {
+    path: String,
+    size: u64,
+    mtime: u128,
+    hash: String,
+}
+
+impl FileEntry {
+    fn read(dir: &Path, path: &str) -> io::Result<FileEntry> {
+        let (size, mtime) = stat(&dir.join(path))?;
+        Ok(FileEntry {
+            path: path.to_string(),
+            size,
+            mtime,
+            hash: hash_file(&dir.join(path))?,
+        })
+    }
+
+    /// Check that the file in the entry is unchanged since it was cached.
+    /// Hashing it is slower, so it's only done with `check_contents`.
+    fn verify(&self, dir: &Path, check_contents: bool) -> io::Result<()> {
+        let path = dir.join(&self.path);
+        let is_unchanged = stat(&path)? == (self.size, self.mtime)
+            && (!check_contents || hash_file(&path)? == self.hash);
+        if is_unchanged {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} was modified after it was cached", self.path),
+            ))
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "path": self.path,
+            "size": self.size,
+            // Nanoseconds don't fit in a JSON number, store them as a string.
+            "mtime": self.mtime.to_string(),
+            "hash": self.hash,
+        })
+    }
+}
+
+/// Return a file's size and modification time, in nanoseconds since the Unix
+/// epoch.
+fn stat(path: &Path) -> io::Result<(u64, u128)> {
+    let metadata = fs::metadata(path)?;
+    let mtime = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    Ok((metadata.len(), mtime))
+}
+
+/// Read an entry's manifest. Entries can come from a remote cache, so
+/// manifests listing paths outside the entry and package are invalid.
+fn read_manifest(entry_dir: &Path) -> io::Result<Manifest> {
+    let content = fs::read_to_string(entry_dir.join(MANIFEST_FILE))?;
+    let json = serde_json::from_str::<serde_json::Value>(&content)?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid cache manifest");
+    if json["version"].as_u64() != Some(CACHE_VERSION.into()) {
+        return Err(invalid());
+    }
+    let format = match json["format"].as_str() {
+        Some(name) => CacheFormat::from_name(name).ok_or_else(invalid)?,
+        None => CacheFormat::Directory,
+    };
+    let files = json["files"].as_array().ok_or_else(invalid)?;
+    let files = match format {
+        CacheFormat::Directory => files
+            .iter()
+            .map(|file| {
+                Some(FileEntry {
+                    path: file["path"]
+                        .as_str()
+                        .filter(|path| archive::is_inside(path))?
+                        .to_string(),
+                    size: file["size"].as_u64()?,
+                    mtime: file["mtime"].as_str()?.parse().ok()?,
+                    hash: file["hash"].as_str()?.to_string(),
+                })
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(ManifestFiles::Directory),
+        _ => files
+            .iter()
+            .map(|file| {
+                Some(ArchivedFile {
+                    path: file["path"]
+                        .as_str()
+                        .filter(|path| archive::is_inside(path))?
+                        .to_string(),
+                    size: file["size"].as_u64()?,
+                    mode: file["mode"].as_u64()? as u32,
+                    hash: file["hash"].as_str()?.to_string(),
+                })
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(ManifestFiles::Archive),
+    }
+    .ok_or_else(invalid)?;
+    Ok(Manifest {
+        format,
+        files,
+        build_duration: json["buildDurationMs"].as_u64().map(Duration::from_millis),
+    })
+}
+
+/// Populate a cache entry directory with the outputs and, last, its manifest.
+fn write_entry(
+    package_dir: &Path,
+    files: &[String],
+    strategy: RestoreStrategy,
+    format: CacheFormat,
+    build_duration: Duration,
+    temp_dir: &Path,
+) -> io::Result<()> {
+    let data = temp_dir.join(format.data());
+    let mut entries = Vec::new();
+    match format.compression() {
+        None => {
+            fs::create_dir_all(&data)?;
+            for file in files {
+                transfer_file(strategy.for_save(), package_dir, &data, file)?;
+                entries.push(FileEntry::read(&data, file)?.to_json());
+            }
+        }
+        Some(compression) => {
+            fs::create_dir_all(temp_dir)?;
+            for file in archive::write(&data, compression, package_dir, files)? {
+                entries.push(serde_json::json!({
+                    "path": file.path,
+                    "size": file.size,
+                    "mode": file.mode,
+                    "hash": file.hash,
+                }));
+            }
+        }
+    }
+
+    let manifest = serde_json::json!({
+        "version": CACHE_VERSION,
+        "format": format.name(),
+        "files": entries,
+        "buildDurationMs": build_duration.as_millis() as u64,
+    });
+    fs::write(
+        temp_dir.join(MANIFEST_FILE),
+        serde_json::to_string_pretty(&manifest)?,
+    )
+}
+
+/// Return every file matched by the outputs, relative to the package
+/// directory. Directories include all the files inside them. Outputs that
+/// aren't globs must exist unless `allow_missing` is set, while globs may
+/// match nothing.
+fn output_files(
+    package_dir: &Path,
+    outputs: &[String],
+    allow_missing: bool,
+) -> io::Result<Vec<String>> {
+    let mut files = BTreeSet::new();
+    for output in outputs {
+        let is_glob = Pattern::escape(output) != *output;
+        let paths = if is_glob {
+            globby(package_dir, vec![output.clone()], vec![], false)
+        } else {
+            let path = package_dir.join(output);
+            if !path.exists() && !allow_missing {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Output {path:?} doesn't exist"),
+                ));
+            }
+            vec![path]
+        };
+
+        for path in paths {
+            if path.is_dir() {
+                let relative = path.strip_prefix(package_dir).unwrap_or(&path);
+                files.extend(
+                    list_files(&path)?
+                        .into_iter()
+                        .map(|file| format!("{}/{file}", to_slash(relative))),
+                );
+            } else if path.is_file() {
+                files.insert(to_slash(path.strip_prefix(package_dir).unwrap_or(&path)));
+            }
+        }
+    }
+    Ok(files.into_iter().collect())
+}
+
+/// Transfer a file, relative to `from`, to the same relative path inside `to`.
+fn transfer_file(strategy: RestoreStrategy, from: &Path, to: &Path, file: &str) -> io::Result<()> {
+    let dest = to.join(file);
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    strategy.transfer(&from.join(file), &dest)
+}
+
+/// Return every file inside a directory, relative to it.
+fn list_files(dir: &Path) -> io::Result<Vec<String>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(current) = dirs.pop() {
+        for entry in fs::read_dir(&current)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else {
+                files.push(to_slash(path.strip_prefix(dir).unwrap_or(&path)));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn to_slash(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
+
+/// Return a new temporary path next to an entry. Paths are unique within the
+/// process too, since `buildc cache-server` writes entries from many threads.
+fn temp_entry_dir(entry_dir: &Path) -> PathBuf {
+    static TEMP_PATHS: AtomicUsize = AtomicUsize::new(0);
+
+    let mut name = entry_dir.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(
+        "{TEMP_MARKER}{}-{}",
+        std::process::id(),
+        TEMP_PATHS.fetch_add(1, Ordering::Relaxed)
+    ));
+    entry_dir.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    use super::{
+        archive, clean_old_layout, download, inputs_key, output_files, restore, save,
+        unlink_outputs, upload, CacheFormat, FileEntry, LocalCache, RestoreStrategy,
+    };
+    use crate::remote::CacheBackend;
+    use crate::test_utils::{test_dir, CTX};
+
+    /// Create a package inside `dir` that has been built to `dist/index.js`.
+    fn built_package(dir: &Path) -> PathBuf {
+        let package_dir = dir.join("package");
+        fs::create_dir_all(package_dir.join("dist")).unwrap();
+        fs::write(package_dir.join("dist/index.js"), "built").unwrap();
+        package_dir
+    }
+
+    #[test]
+    fn test_output_files() {
+        let dir = test_dir("outputs");
+        for file in [
+            "dist/index.js",
+            "dist/chunks/a.js",
+            "types/index.d.ts",
+            "schema.json",
+        ] {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        let outputs = |outputs: &[&str]| {
+            output_files(
+                &dir,
+                &outputs.iter().map(|s| s.to_string()).collect::<Vec<_>>(),
+                false,
+            )
+        };
+
+        let files = outputs(&["dist", "types/*.d.ts", "*.json", "*.md"]).unwrap();
+        let missing = outputs(&["dist", "lib"]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            files,
+            vec![
+                "dist/chunks/a.js",
+                "dist/index.js",
+                "schema.json",
+                "types/index.d.ts"
+            ]
+        );
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn test_clean_old_layout() {
+        let dir = test_dir("old-layout");
+        let old_entry = dir.join("0cc175b9c0f1b6a831c399e269772661");
+        let entry =
+            dir.join("build/af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262");
+        fs::create_dir_all(old_entry.join("dist")).unwrap();
+        fs::create_dir_all(&entry).unwrap();
+        fs::write(dir.join("hashes.json"), "{}").unwrap();
+
+        clean_old_layout(&CTX, &dir);
+        let old_entry_exists = old_entry.exists();
+        let entry_exists = entry.exists();
+        let memo_exists = dir.join("hashes.json").exists();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(!old_entry_exists);
+        assert!(entry_exists);
+        assert!(memo_exists);
+    }
+
+    #[test]
+    fn test_inputs_key() {
+        let dir = test_dir("inputs-key");
+
+        let key = inputs_key(&dir).unwrap();
+        let same_key = inputs_key(&dir).unwrap();
+        let other_key = inputs_key(&dir.join("other")).unwrap();
+        let files = fs::read_dir(&dir).unwrap().count();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(key, same_key);
+        assert_ne!(key, other_key);
+        assert_eq!(files, 2);
+    }
+
+    #[test]
+    fn test_hardlink_edits_are_detected() {
+        let dir = test_dir("hardlink");
+        let package_dir = built_package(&dir);
+        let entry_dir = dir.join("cache/entry");
+        let index = package_dir.join("dist/index.js");
+        // Overwrite a file in place, keeping its size and modification time
+        let edit_in_place = |contents: &[u8]| {
+            let mtime = fs::metadata(&index).unwrap().modified().unwrap();
+            let mut file = fs::OpenOptions::new().write(true).open(&index).unwrap();
+            file.write_all(contents).unwrap();
+            file.set_modified(mtime).unwrap();
+        };
+
+        let strategy = RestoreStrategy::Hardlink;
+        save(
+            &CTX,
+            &package_dir,
+            &["dist".to_string()],
+            strategy,
+            CacheFormat::Directory,
+            Duration::ZERO,
+            &entry_dir,
+        )
+        .unwrap();
+        // Saving copies, so editing the build's own files leaves the entry alone
+        edit_in_place(b"BUILT");
+        let saved = restore(&CTX, &package_dir, strategy, &entry_dir);
+        let saved_output = fs::read_to_string(&index);
+
+        // Restoring links, so editing a restored file writes into the entry
+        edit_in_place(b"EDITS");
+        let edited = restore(&CTX, &package_dir, strategy, &entry_dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(saved.is_ok());
+        assert_eq!(saved_output.unwrap(), "built");
+        assert!(edited.is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hardlinked_restores_are_read_only() {
+        let dir = test_dir("readonly");
+        let package_dir = built_package(&dir);
+        let entry_dir = dir.join("cache/entry");
+        let index = package_dir.join("dist/index.js");
+        let outputs = ["dist".to_string()];
+        let is_readonly = |path: &Path| fs::metadata(path).unwrap().permissions().readonly();
+
+        let strategy = RestoreStrategy::Hardlink;
+        save(
+            &CTX,
+            &package_dir,
+            &outputs,
+            strategy,
+            CacheFormat::Directory,
+            Duration::ZERO,
+            &entry_dir,
+        )
+        .unwrap();
+        restore(&CTX, &package_dir, strategy, &entry_dir).unwrap();
+        let restored = is_readonly(&index);
+        // Before a build, outputs get their own copy the build can write to
+        unlink_outputs(&package_dir, &outputs).unwrap();
+        let unlinked = is_readonly(&index);
+        let cached = is_readonly(&entry_dir.join("outputs/dist/index.js"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(restored);
+        assert!(!unlinked);
+        assert!(cached);
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let dir = test_dir("archive");
+        let package_dir = built_package(&dir);
+        let entry_dir = dir.join("cache/entry");
+        fs::create_dir_all(package_dir.join("dist/bin")).unwrap();
+        fs::write(package_dir.join("dist/bin/cli.js"), "#!/usr/bin/env node").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let permissions = fs::Permissions::from_mode(0o755);
+            fs::set_permissions(package_dir.join("dist/bin/cli.js"), permissions).unwrap();
+        }
+
+        let strategy = RestoreStrategy::Auto;
+        for format in [CacheFormat::TarZstd, CacheFormat::TarGzip] {
+            save(
+                &CTX,
+                &package_dir,
+                &["dist".to_string()],
+                strategy,
+                format,
+                Duration::from_millis(1500),
+                &entry_dir,
+            )
+            .unwrap();
+            fs::remove_dir_all(package_dir.join("dist")).unwrap();
+            let build_duration = restore(&CTX, &package_dir, strategy, &entry_dir).unwrap();
+
+            assert_eq!(build_duration, Some(Duration::from_millis(1500)));
+            assert!(entry_dir
+                .join(format!("outputs.{}", format.name()))
+                .is_file());
+            assert!(!entry_dir.join("outputs").exists());
+            assert_eq!(
+                fs::read_to_string(package_dir.join("dist/index.js")).unwrap(),
+                "built"
+            );
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let metadata = fs::metadata(package_dir.join("dist/bin/cli.js")).unwrap();
+                assert_eq!(metadata.permissions().mode() & 0o777, 0o755);
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_remote_round_trip() {
+        let dir = test_dir("remote");
+        let package_dir = built_package(&dir);
+        let local = LocalCache::new(dir.join("cache"));
+        let other = LocalCache::new(dir.join("other-cache"));
+        let remote = LocalCache::new(dir.join("remote"));
+
+        let strategy = RestoreStrategy::Copy;
+        save(
+            &CTX,
+            &package_dir,
+            &["dist".to_string()],
+            strategy,
+            CacheFormat::Directory,
+            Duration::ZERO,
+            &local.entry_dir("a/build/0f3e"),
+        )
+        .unwrap();
+        let other_script = download(&remote, &other, "a/test/0f3e").unwrap();
+        let missing = download(&remote, &other, "a/build/0f3e").unwrap();
+        upload(&remote, &local, "a/build/0f3e").unwrap();
+        let found = download(&remote, &other, "a/build/0f3e").unwrap();
+        fs::remove_dir_all(package_dir.join("dist")).unwrap();
+        let restored = restore(
+            &CTX,
+            &package_dir,
+            strategy,
+            &other.entry_dir("a/build/0f3e"),
+        );
+        let output = fs::read_to_string(package_dir.join("dist/index.js"));
+        let has_artifact = remote.has("a/build/0f3e").unwrap();
+        let has_other_script = remote.has("a/test/0f3e").unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(!missing);
+        assert!(found);
+        assert!(has_artifact);
+        assert!(!other_script);
+        assert!(!has_other_script);
+        assert!(restored.is_ok());
+        assert_eq!(output.unwrap(), "built");
+    }
+
+    #[test]
+    fn test_manifest_paths_outside_the_package_are_rejected() {
+        let dir = test_dir("outside");
+        let package_dir = built_package(&dir);
+        let crafted = dir.join("crafted");
+        let local = LocalCache::new(dir.join("cache"));
+        let artifact = dir.join("artifact.tar.zst");
+
+        // An entry, like one from an untrusted remote, whose manifest lists a
+        // file that would be restored next to the package.
+        let strategy = RestoreStrategy::Copy;
+        save(
+            &CTX,
+            &package_dir,
+            &["dist".to_string()],
+            strategy,
+            CacheFormat::Directory,
+            Duration::ZERO,
+            &crafted,
+        )
+        .unwrap();
+        fs::write(crafted.join("outside.js"), "malicious").unwrap();
+        let file = FileEntry::read(&crafted.join("outputs"), "../outside.js").unwrap();
+        let manifest = fs::read_to_string(crafted.join("manifest.json")).unwrap();
+        let mut manifest = serde_json::from_str::<serde_json::Value>(&manifest).unwrap();
+        manifest["files"] = serde_json::json!([file.to_json()]);
+        fs::write(crafted.join("manifest.json"), manifest.to_string()).unwrap();
+        archive::pack_dir(&crafted, &artifact).unwrap();
+
+        let put = local.put("a/build/0f3e", &artifact);
+        let has_entry = local.has("a/build/0f3e").unwrap();
+        let restored = restore(&CTX, &package_dir, strategy, &crafted);
+        let escaped = dir.join("outside.js").exists();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(put.is_err());
+        assert!(!has_entry);
+        assert!(restored.is_err());
+        assert!(!escaped);
+    }
+}
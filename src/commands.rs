@@ -1,13 +1,20 @@
 use std::env;
+use std::fs;
 use std::path::PathBuf;
 use std::process::exit;
+use std::time::{Duration, Instant};
 
+use crate::cache;
 use crate::colors::{BOLD, CYAN, DIM, GREEN, MAGENTA, RED, RESET, YELLOW};
 use crate::ctx::Ctx;
-use crate::graph::{Graph, Package};
-use crate::hash::hash_package;
+use crate::graph::{format_cycle, task_id, Graph, Package, TaskNode, BUILD_SCRIPT};
+use crate::hash::{bundled_dependency_hashes, hash_package, InputChange, PackageInputs};
+use crate::lock::PackageLock;
 use crate::monorepo;
 use crate::monorepo::Monorepo;
+use crate::remote::{self, CacheBackend};
+use crate::scheduler;
+use crate::scheduler::Job;
 
 pub fn build(ctx: &Ctx) -> Result<(), Box<dyn std::error::Error>> {
     if env::var("INSIDE_BUILDC").unwrap_or_default() == "true" {
@@ -29,14 +36,20 @@ pub fn build(ctx: &Ctx) -> Result<(), Box<dyn std::error::Error>> {
 
     let monorepo = require_monorepo(ctx);
     let graph = monorepo.to_graph();
+    check_cycles(&monorepo, &graph);
     let active_package = require_active_package(ctx, &graph);
 
-    let dependencies = graph
-        .get_package_dependencies_build_order(&active_package.name)
-        .unwrap();
-    let packages_to_build = [dependencies, vec![active_package]].concat();
+    let roots = [(active_package.name.clone(), BUILD_SCRIPT.to_string())];
+    let mut targets = get_build_targets(&monorepo, &graph, &roots);
+
+    // Dependencies are built with `<pm> run build`, while the active package
+    // runs the command passed after --.
+    let active_id = task_id(&active_package.name, BUILD_SCRIPT);
+    if let Some(target) = targets.iter_mut().find(|target| target.id == active_id) {
+        target.command = Some(ctx.cmd_args.iter().map(|arg| arg.to_string()).collect());
+    }
 
-    build_cached_packages(ctx, &monorepo, packages_to_build);
+    build_cached_packages(ctx, &monorepo, targets);
     Ok(())
 }
 
@@ -50,21 +63,47 @@ pub fn deps(ctx: &Ctx) -> Result<(), Box<dyn std::error::Error>> {
 
     let monorepo = require_monorepo(ctx);
     let graph = monorepo.to_graph();
+    check_cycles(&monorepo, &graph);
     let active_package = require_active_package(ctx, &graph);
-    let dependencies = graph
+    let roots = graph
         .get_package_dependencies_build_order(&active_package.name)
-        .unwrap();
+        .unwrap()
+        .into_iter()
+        .map(|package| (package.name, BUILD_SCRIPT.to_string()))
+        .collect::<Vec<_>>();
 
-    build_cached_packages(ctx, &monorepo, dependencies);
+    let targets = get_build_targets(&monorepo, &graph, &roots);
+    build_cached_packages(ctx, &monorepo, targets);
     Ok(())
 }
 
 pub fn all(ctx: &Ctx) -> Result<(), Box<dyn std::error::Error>> {
     let monorepo = require_monorepo(ctx);
     let graph = monorepo.to_graph();
-    let dependencies = graph.get_overall_build_order();
+    check_cycles(&monorepo, &graph);
+    let roots = graph
+        .get_overall_build_order()
+        .into_iter()
+        .map(|package| (package.name, BUILD_SCRIPT.to_string()))
+        .collect::<Vec<_>>();
+
+    let targets = get_build_targets(&monorepo, &graph, &roots);
+    build_cached_packages(ctx, &monorepo, targets);
+    Ok(())
+}
 
-    build_cached_packages(ctx, &monorepo, dependencies);
+pub fn run(ctx: &Ctx, script: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let monorepo = require_monorepo(ctx);
+    let graph = monorepo.to_graph();
+    check_cycles(&monorepo, &graph);
+    let roots = graph
+        .get_overall_build_order()
+        .into_iter()
+        .map(|package| (package.name, script.to_string()))
+        .collect::<Vec<_>>();
+
+    let targets = get_build_targets(&monorepo, &graph, &roots);
+    build_cached_packages(ctx, &monorepo, targets);
     Ok(())
 }
 
@@ -72,6 +111,7 @@ pub fn graph(ctx: &Ctx) -> Result<(), Box<dyn std::error::Error>> {
     let monorepo = require_monorepo(ctx);
     let graph = monorepo.to_graph();
     graph.print()?;
+    print_cycles(&monorepo, &graph);
     Ok(())
 }
 
@@ -94,6 +134,124 @@ pub fn clean(ctx: &Ctx) -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// Explain why a package's build isn't cached by comparing its current inputs
+/// with the closest entry in its cache. Defaults to the active package.
+pub fn explain(ctx: &Ctx, package_name: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let monorepo = require_monorepo(ctx);
+    let graph = monorepo.to_graph();
+    check_cycles(&monorepo, &graph);
+    let package = match package_name {
+        Some(name) => require_package(&graph, name),
+        None => require_active_package(ctx, &graph),
+    };
+
+    let target = get_build_target(&monorepo, &graph, &package);
+    if target.cache_command().is_none() {
+        println!(
+            "{GREEN}[buildc] ✓{RESET} {}: Nothing to build",
+            package.name
+        );
+        return Ok(());
+    }
+
+    let (_, cache_dir, inputs) =
+        get_cache_entry(ctx, &monorepo, &target).unwrap_or_else(|code| exit(code));
+    if cache::is_complete(&cache_dir) {
+        println!(
+            "{GREEN}[buildc] ✓{RESET} {}: Cached, no inputs changed",
+            package.name
+        );
+        return Ok(());
+    }
+
+    let closest = cache::entries_with_inputs(cache_dir.parent().unwrap())
+        .into_iter()
+        .filter_map(|(entry_dir, json, saved_at)| {
+            let changes = inputs.diff(&PackageInputs::from_json(&json)?);
+            Some((entry_dir, changes, saved_at))
+        })
+        .min_by_key(|(_, changes, saved_at)| (changes.len(), std::cmp::Reverse(*saved_at)));
+    let Some((entry_dir, changes, _)) = closest else {
+        println!(
+            "{YELLOW}[buildc] !{RESET} {}: Not cached, there are no previous cache entries to compare with",
+            package.name
+        );
+        return Ok(());
+    };
+
+    let entry_hash = entry_dir.file_name().unwrap_or_default().to_string_lossy();
+    println!(
+        "{YELLOW}[buildc] !{RESET} {}: Not cached, {} input(s) changed since the closest cache entry {DIM}({}){RESET}",
+        package.name,
+        changes.len(),
+        &entry_hash[..entry_hash.len().min(12)]
+    );
+    for change in changes {
+        match change {
+            InputChange::Added(input) => println!("  {GREEN}+{RESET} {input}"),
+            InputChange::Removed(input) => println!("  {RED}-{RESET} {input}"),
+            InputChange::Changed(input) => println!("  {YELLOW}~{RESET} {input}"),
+        }
+    }
+    Ok(())
+}
+
+/// Print the hash of each package's build, and with `show_inputs`, the hash of
+/// every file it was computed from. Defaults to the active package, or every
+/// package when run outside of one.
+pub fn hash(
+    ctx: &Ctx,
+    package_names: &[&str],
+    show_inputs: bool,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let monorepo = require_monorepo(ctx);
+    let graph = monorepo.to_graph();
+    // Allowed cycles are only warnings, leave them out of the JSON output.
+    if !json || !monorepo.config.allow_cycles {
+        check_cycles(&monorepo, &graph);
+    }
+    let packages = if package_names.is_empty() {
+        match graph.find_active_package() {
+            Some(package) => vec![package],
+            None => graph.get_overall_build_order(),
+        }
+    } else {
+        package_names
+            .iter()
+            .map(|name| require_package(&graph, name))
+            .collect()
+    };
+
+    let mut output = serde_json::Map::new();
+    for package in packages {
+        let target = get_build_target(&monorepo, &graph, &package);
+        let (hash, inputs) = hash_target(ctx, &monorepo, &target).unwrap_or_else(|code| exit(code));
+
+        if json {
+            let mut entry = serde_json::json!({ "hash": hash });
+            if show_inputs {
+                entry["inputs"] = inputs.to_json();
+            }
+            output.insert(package.name, entry);
+            continue;
+        }
+        println!("{} {hash}", package.name);
+        if show_inputs {
+            for (path, hash) in &inputs.files {
+                println!("  {hash} {path}");
+            }
+            for (path, hash) in &inputs.global_files {
+                println!("  {hash} {path} (global)");
+            }
+        }
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&output)?);
+    }
+    Ok(())
+}
+
 /// Find the monorepo the cwd is inside, or exit.
 fn require_monorepo(ctx: &Ctx) -> Monorepo {
     let monorepo = monorepo::find();
@@ -131,142 +289,414 @@ fn require_active_package(ctx: &Ctx, graph: &Graph) -> Package {
     active_package
 }
 
-/// Build a list of packages in the order passed in (from 0 to n), restoring each from cache if already built.
-/// Exit if something goes wrong.
-fn build_cached_packages(ctx: &Ctx, monorepo: &Monorepo, packages: Vec<Package>) {
-    if ctx.is_debug {
+/// Find a package by name, or exit.
+fn require_package(graph: &Graph, name: &str) -> Package {
+    graph.get_package(name).unwrap_or_else(|| {
+        eprintln!("{RED}{BOLD}[buildc] ✘{RESET} Package {name:?} not found");
+        exit(1)
+    })
+}
+
+/// Print any dependency cycles, then exit unless cycles are allowed in the
+/// root config.
+fn check_cycles(monorepo: &Monorepo, graph: &Graph) {
+    print_cycles(monorepo, graph);
+    if !graph.cycles.is_empty() && !monorepo.config.allow_cycles {
         println!(
-            "{DIM}[buildc] → Packages to build: {:?}{RESET}",
-            packages
-                .iter()
-                .map(|package| &package.name)
-                .collect::<Vec<_>>()
+            "{RED}{BOLD}[buildc] ✘{RESET} Cannot determine build order. Remove the cycle or set {CYAN}\"allowCycles\": true{RESET} in your buildc config"
         );
+        exit(1);
     }
+}
 
-    // TODO: Add lockfile around this loop to prevent multiple processes from running multiple builds at the same time
-    for package in packages {
-        build_cached_package(ctx, monorepo, package);
+/// Print each dependency cycle along with the package.json files involved.
+fn print_cycles(monorepo: &Monorepo, graph: &Graph) {
+    let (color, icon) = if monorepo.config.allow_cycles {
+        (YELLOW, "!")
+    } else {
+        (RED, "✘")
+    };
+    for cycle in &graph.cycles {
+        println!(
+            "{color}{BOLD}[buildc] {icon}{RESET} Dependency cycle: {}",
+            format_cycle(cycle)
+        );
+        for name in &cycle[..cycle.len() - 1] {
+            if let Some(package) = graph.get_package(name) {
+                let package_json = package.dir.join("package.json");
+                println!(
+                    "    {DIM}{name}: {}{RESET}",
+                    package_json
+                        .strip_prefix(&monorepo.root)
+                        .unwrap_or(&package_json)
+                        .display()
+                );
+            }
+        }
     }
 }
 
-/// Build a single package or restore it from cache if already build.
+/// A package to build, and the script to build it with.
+struct BuildTarget {
+    /// Task ID, see `graph::task_id`.
+    id: String,
+    package: Package,
+    /// The package.json script to run. Each script is cached separately, and
+    /// only the build script's output directory is cached.
+    script: String,
+    /// IDs of the targets that need to finish first.
+    dependencies: Vec<String>,
+    /// Command to run instead of `<pm> run <script>`. It's part of the cache key.
+    command: Option<Vec<String>>,
+    /// Dependencies bundled into the package's output, in build order. Their
+    /// hashes are part of the cache key.
+    bundled_dependencies: Vec<Package>,
+}
+
+impl BuildTarget {
+    fn is_build(&self) -> bool {
+        self.script == BUILD_SCRIPT
+    }
+
+    /// Name shown in logs. Non-build scripts include the script name, like
+    /// `a#test`.
+    fn label(&self) -> String {
+        if self.is_build() {
+            self.package.name.clone()
+        } else {
+            self.id.clone()
+        }
+    }
+
+    /// The command the target is cached under, `None` when there's nothing to
+    /// run. Builds, `hash` and `explain` all key entries with it.
+    fn cache_command(&self) -> Option<String> {
+        match &self.command {
+            Some(command) => Some(command.join(" ")),
+            None => self.package.script_command(&self.script),
+        }
+    }
+
+    /// The outputs to cache and restore for this script. Only the build
+    /// script has outputs.
+    fn outputs(&self) -> &[String] {
+        if self.is_build() {
+            &self.package.config.outputs
+        } else {
+            &[]
+        }
+    }
+}
+
+impl From<TaskNode> for BuildTarget {
+    fn from(node: TaskNode) -> Self {
+        BuildTarget {
+            id: node.id(),
+            package: node.package,
+            script: node.task,
+            dependencies: node.dependencies,
+            command: None,
+            bundled_dependencies: Vec::new(),
+        }
+    }
+}
+
+/// Return the targets needed to run the root tasks, following the root
+/// config's pipeline, in build order.
+fn get_build_targets(
+    monorepo: &Monorepo,
+    graph: &Graph,
+    roots: &[(String, String)],
+) -> Vec<BuildTarget> {
+    graph
+        .get_task_build_order(roots, &|task| monorepo.config.task_dependencies(task))
+        .into_iter()
+        .map(|node| {
+            let mut target = BuildTarget::from(node);
+            target.bundled_dependencies =
+                graph.get_bundled_dependencies_build_order(&target.package.name);
+            target
+        })
+        .collect()
+}
+
+/// Return the target building a single package.
+fn get_build_target(monorepo: &Monorepo, graph: &Graph, package: &Package) -> BuildTarget {
+    let roots = [(package.name.clone(), BUILD_SCRIPT.to_string())];
+    let id = task_id(&package.name, BUILD_SCRIPT);
+    get_build_targets(monorepo, graph, &roots)
+        .into_iter()
+        .find(|target| target.id == id)
+        .unwrap()
+}
+
+/// Build a list of packages, restoring each from cache if already built.
+/// Packages are started in the order passed in (from 0 to n) as soon as their
+/// dependencies are built, running up to `ctx.concurrency` builds at a time.
 /// Exit if something goes wrong.
-fn build_cached_package(ctx: &Ctx, monorepo: &Monorepo, package: Package) {
-    let build_script = package.build_script.clone();
-    if package.build_script.is_none() {
+fn build_cached_packages(ctx: &Ctx, monorepo: &Monorepo, targets: Vec<BuildTarget>) {
+    if ctx.is_debug {
         println!(
-            "{GREEN}[buildc] ✓{RESET} {}: Nothing to build",
-            package.name
+            "{DIM}[buildc] → Tasks to run: {:?}{RESET}",
+            targets.iter().map(|target| &target.id).collect::<Vec<_>>()
         );
-        return;
+        println!("{DIM}[buildc] → Concurrency: {}{RESET}", ctx.concurrency);
     }
-    let build_script = build_script.unwrap();
 
-    let mut args = monorepo.package_manager.run_cmd();
-    args.push("build");
+    let jobs = targets
+        .into_iter()
+        .map(|target| Job {
+            id: target.id.clone(),
+            dependencies: target.dependencies.clone(),
+            value: target,
+        })
+        .collect();
+    scheduler::run(jobs, ctx.concurrency, |target| {
+        build_cached_package(ctx, monorepo, target)
+    })
+    .unwrap_or_else(|code| exit(code));
+}
+
+/// Build a single package or restore it from cache if already build. Returns
+/// the exit code of the build command if it fails.
+fn build_cached_package(ctx: &Ctx, monorepo: &Monorepo, target: &BuildTarget) -> Result<(), i32> {
+    let package = &target.package;
+    let (args, display_command) = match &target.command {
+        Some(command) => (
+            command.iter().map(String::as_str).collect::<Vec<_>>(),
+            command.join(" "),
+        ),
+        None => {
+            let Some(script) = package.scripts.get(&target.script).cloned() else {
+                if target.is_build() {
+                    println!(
+                        "{GREEN}[buildc] ✓{RESET} {}: Nothing to build",
+                        target.label()
+                    );
+                } else if ctx.is_debug {
+                    println!(
+                        "{DIM}[buildc] → Skipping {}, no {:?} script{RESET}",
+                        package.name, target.script
+                    );
+                }
+                return Ok(());
+            };
+            let mut args = monorepo.package_manager.run_cmd();
+            args.push(&target.script);
+            // Even though we'll actually be calling `<pm> run <script>`, print
+            // the script instead because it is more meaningful to the user.
+            (args, script)
+        }
+    };
+
     if ctx.is_debug {
         println!(
             "{DIM}[buildc] → Running {args:?} in {:?}{RESET}",
             package.dir.strip_prefix(monorepo.root.clone()).unwrap()
         );
     }
-
-    // Even though we'll actually be calling `<pm> run build`, print the build
-    // script instead because it is more meaningful to the user.
     println!(
-        "{MAGENTA}[buildc] ◐{RESET} {}: {CYAN}{build_script}{RESET}",
-        package.name
+        "{MAGENTA}[buildc] ◐{RESET} {}: {CYAN}{display_command}{RESET}",
+        target.label()
     );
 
-    let cache_dir = get_package_cache_dir(ctx, monorepo, &package);
+    // Hold the package's lock while checking and writing its cache so other
+    // buildc processes don't build the same package at the same time.
+    let package_cache_dir = package_cache_dir(monorepo, package);
+    let _lock = PackageLock::acquire(ctx, &package_cache_dir, &target.label()).map_err(|e| {
+        println!("{RED}{BOLD}[buildc] ✘{RESET} Error acquiring lock: {}", e);
+        1
+    })?;
+    cache::clean_old_layout(ctx, &package_cache_dir);
+    cache::clean_incomplete(ctx, &package_cache_dir.join(&target.script));
+
+    let (key, cache_dir, inputs) = get_cache_entry(ctx, monorepo, target)?;
     if ctx.is_debug {
         println!("{DIM}[buildc] → Cache dir: {:?}{RESET}", cache_dir);
     }
 
-    if cache_dir.exists() && package.config.cache {
-        restore_package_cache(ctx, &package, cache_dir);
-        println!("{GREEN}[buildc] ✓{RESET} {}: Cached!", package.name);
-        return;
+    let remote = monorepo.remote_cache().filter(|_| package.config.cache);
+    let mut download_duration = Duration::ZERO;
+    if let Some(remote) = remote.filter(|_| !cache::is_complete(&cache_dir)) {
+        let started_at = Instant::now();
+        match cache::download(remote, &monorepo.local_cache(), &key) {
+            Ok(true) => download_duration = started_at.elapsed(),
+            Ok(false) if ctx.is_debug => println!(
+                "{DIM}[buildc] → Not in the remote cache at {}{RESET}",
+                remote.location()
+            ),
+            Ok(false) => {}
+            Err(e) => println!(
+                "{YELLOW}[buildc] !{RESET} {}: Error downloading from the remote cache, {}",
+                target.label(),
+                e
+            ),
+        }
     }
 
-    exec_in_dir(&package.dir, args);
-
-    if package.config.cache {
-        cache_package_output(ctx, &package, cache_dir);
+    let strategy = package.config.restore_strategy;
+    if package.config.cache && cache::is_complete(&cache_dir) {
+        let started_at = Instant::now();
+        match cache::restore(ctx, &package.dir, strategy, &cache_dir) {
+            Ok(build_duration) => {
+                let restore_duration = started_at.elapsed();
+                let downloaded = if download_duration.is_zero() {
+                    String::new()
+                } else {
+                    format!("downloaded in {}, ", format_duration(download_duration))
+                };
+                match build_duration {
+                    Some(build_duration) => println!(
+                        "{GREEN}[buildc] ✓{RESET} {}: Cached! {DIM}({downloaded}restored in {}, saved {}){RESET}",
+                        target.label(),
+                        format_duration(restore_duration),
+                        format_duration(
+                            build_duration.saturating_sub(download_duration + restore_duration)
+                        ),
+                    ),
+                    None => println!("{GREEN}[buildc] ✓{RESET} {}: Cached!", target.label()),
+                }
+                return Ok(());
+            }
+            Err(e) => {
+                println!(
+                    "{YELLOW}[buildc] !{RESET} {}: Discarding cache entry, {}",
+                    target.label(),
+                    e
+                );
+                let _ = fs::remove_dir_all(&cache_dir);
+            }
+        }
     }
 
-    println!("{GREEN}[buildc] ✓{RESET} {}: Built", package.name);
-}
-
-/// Return the path to a package's cache based on it's current hash.
-fn get_package_cache_dir(ctx: &Ctx, monorepo: &Monorepo, package: &Package) -> PathBuf {
-    let (package_hash, file_hashes) = hash_package(package).unwrap_or_else(|e| {
+    // Outputs restored with hardlinks share their contents with the cache, so
+    // they need their own copy before the build overwrites them.
+    cache::unlink_outputs(&package.dir, target.outputs()).map_err(|e| {
         println!(
-            "{RED}{BOLD}[buildc] ✘{RESET} Error computing package hash: {}",
+            "{RED}{BOLD}[buildc] ✘{RESET} Error preparing outputs: {}",
             e
         );
-        exit(1)
-    });
-    if ctx.is_debug {
-        println!("{DIM}[buildc] → File hashes:\n{file_hashes}{RESET}");
-        println!("{DIM}[buildc] → Package hash: {package_hash}{RESET}");
+        1
+    })?;
+
+    let started_at = Instant::now();
+    exec_in_dir(&package.dir, args).inspect_err(|_| {
+        println!(
+            "{RED}{BOLD}[buildc] ✘{RESET} {}: {} failed",
+            target.label(),
+            target.script
+        );
+    })?;
+
+    if package.config.cache {
+        cache::save(
+            ctx,
+            &package.dir,
+            target.outputs(),
+            strategy,
+            package.config.cache_format,
+            started_at.elapsed(),
+            &cache_dir,
+        )
+        .map_err(|e| {
+            println!("{RED}{BOLD}[buildc] ✘{RESET} Error caching output: {}", e);
+            1
+        })?;
+        if let Err(e) = cache::save_inputs(&cache_dir, &inputs.to_json()) {
+            println!(
+                "{YELLOW}[buildc] !{RESET} {}: Error saving cache inputs: {}",
+                target.label(),
+                e
+            );
+        }
+        if let Some(remote) = remote {
+            if let Err(e) = cache::upload(remote, &monorepo.local_cache(), &key) {
+                println!(
+                    "{YELLOW}[buildc] !{RESET} {}: Error uploading to the remote cache, {}",
+                    target.label(),
+                    e
+                );
+            }
+        }
     }
 
-    monorepo
-        .cache_dir()
-        .join(package.name.clone())
-        .join(package_hash)
+    if target.is_build() {
+        println!("{GREEN}[buildc] ✓{RESET} {}: Built", target.label());
+    } else {
+        println!("{GREEN}[buildc] ✓{RESET} {}: Done", target.label());
+    }
+    Ok(())
 }
 
-/// Copy the cache output to the package's output directory.
-fn restore_package_cache(ctx: &Ctx, package: &Package, cache_dir: PathBuf) {
-    let out_dir = package.absolute_out_dir();
-    if ctx.is_debug {
-        println!("{DIM}[buildc] → Restoring {cache_dir:?} to {out_dir:?}");
-    }
-    std::fs::create_dir_all(&out_dir).unwrap();
-    let mut copy_options = fs_extra::dir::CopyOptions::default();
-    copy_options.overwrite = true;
-    copy_options.content_only = true;
-    fs_extra::dir::copy(&cache_dir, &out_dir, &copy_options).unwrap_or_else(|e| {
-        println!("{RED}{BOLD}[buildc] ✘{RESET} Error restoring cache: {}", e);
-        exit(1);
-    });
+/// Return the key of a target's cache entry based on its current hash, see
+/// `CacheBackend`, the entry's path in `.cache`, and the inputs the hash was
+/// computed from.
+fn get_cache_entry(
+    ctx: &Ctx,
+    monorepo: &Monorepo,
+    target: &BuildTarget,
+) -> Result<(String, PathBuf, PackageInputs), i32> {
+    let (package_hash, inputs) = hash_target(ctx, monorepo, target)?;
+    let key = remote::entry_key(&target.package.name, &target.script, &package_hash);
+    let cache_dir = monorepo.local_cache().entry_dir(&key);
+    Ok((key, cache_dir, inputs))
 }
 
-/// Copy the package's output directory to the cache directory.
-fn cache_package_output(ctx: &Ctx, package: &Package, cache_dir: PathBuf) {
-    let out_dir = package.absolute_out_dir();
+/// Hash a target's inputs, including the dependencies it bundles and the
+/// command it's cached under. Returns an exit code if it fails.
+fn hash_target(
+    ctx: &Ctx,
+    monorepo: &Monorepo,
+    target: &BuildTarget,
+) -> Result<(String, PackageInputs), i32> {
+    let package = &target.package;
+    let command = target.cache_command().unwrap_or_default();
+    let (package_hash, inputs) =
+        bundled_dependency_hashes(ctx, monorepo, package, &target.bundled_dependencies)
+            .and_then(|bundled| hash_package(ctx, monorepo, package, &command, &bundled))
+            .map_err(|e| {
+                println!(
+                    "{RED}{BOLD}[buildc] ✘{RESET} Error computing package hash: {}",
+                    e
+                );
+                1
+            })?;
     if ctx.is_debug {
-        println!("{DIM}[buildc] → Caching {out_dir:?} to {cache_dir:?}");
-    }
-
-    if !out_dir.exists() {
         println!(
-            "{RED}{BOLD}[buildc] ✘{RESET} Output directory {:?} doesn't exist, cannot cache",
-            out_dir
+            "{DIM}[buildc] → Hash inputs:\n{}{RESET}",
+            inputs.lines().join("\n")
         );
-        exit(1);
+        println!("{DIM}[buildc] → Package hash: {package_hash}{RESET}");
     }
+    Ok((package_hash, inputs))
+}
 
-    std::fs::create_dir_all(&cache_dir).unwrap();
-    let mut copy_options = fs_extra::dir::CopyOptions::default();
-    copy_options.overwrite = true;
-    copy_options.content_only = true;
-    fs_extra::dir::copy(&out_dir, &cache_dir, &copy_options).unwrap_or_else(|e| {
-        println!("{RED}{BOLD}[buildc] ✘{RESET} Error caching output: {}", e);
-        exit(1);
-    });
+/// Return the directory holding all of a package's cache entries.
+fn package_cache_dir(monorepo: &Monorepo, package: &Package) -> PathBuf {
+    monorepo.cache_dir().join(package.name.clone())
 }
 
-/// Execute a command inside a directory. Continue on success, exit if the command failed.
-fn exec_in_dir(dir: &PathBuf, args: Vec<&str>) {
-    exec_child_command(
-        std::process::Command::new(args[0])
-            .args(args[1..].iter())
-            .current_dir(&dir),
-    );
+/// Format a duration for logs, like `250ms` or `3.2s`.
+fn format_duration(duration: Duration) -> String {
+    if duration.as_secs() == 0 {
+        format!("{}ms", duration.as_millis())
+    } else {
+        format!("{:.1}s", duration.as_secs_f64())
+    }
+}
+
+/// Execute a command inside a directory. Returns the command's exit code if it failed.
+fn exec_in_dir(dir: &PathBuf, args: Vec<&str>) -> Result<(), i32> {
+    let status = std::process::Command::new(args[0])
+        .args(args[1..].iter())
+        .current_dir(dir)
+        .env("INSIDE_BUILDC", "true")
+        .status();
+    match status.map(|status| status.code()) {
+        Ok(Some(0)) => Ok(()),
+        Ok(Some(code)) => Err(code),
+        Ok(None) | Err(_) => Err(1),
+    }
 }
 
 /// Execute a command as a child process. Continue on success, exit if the command failed.
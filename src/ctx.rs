@@ -1,4 +1,6 @@
 pub struct Ctx<'a> {
     pub is_debug: bool,
     pub cmd_args: Vec<&'a str>,
+    /// Max number of packages to build at the same time.
+    pub concurrency: usize,
 }
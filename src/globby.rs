@@ -1,18 +1,34 @@
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 use glob::glob_with;
 use glob::Pattern;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
 
+/// Return all paths inside `dir` matching any of the include globs and none of
+/// the exclude globs. Both kinds of globs are relative to `dir`.
+///
+/// With `gitignore`, paths ignored by git are left out as well. An include
+/// glob that explicitly points at an ignored path, like `generated/**` when
+/// `generated/` is ignored, still matches it.
 pub fn globby(
-    dir: &PathBuf,
+    dir: &Path,
     include_globs: Vec<String>,
     exclude_globs: Vec<String>,
+    gitignore: bool,
 ) -> Vec<PathBuf> {
     let mut matches = Vec::new();
+    let mut ignores = if gitignore {
+        GitIgnores::find(dir)
+    } else {
+        None
+    };
 
+    let escaped_dir = Pattern::escape(&dir.to_string_lossy());
     let exclude_patterns = exclude_globs
         .iter()
-        .map(|glob| Pattern::new(glob).unwrap())
+        .map(|glob| Pattern::new(&format!("{escaped_dir}/{glob}")).unwrap())
         .collect::<Vec<_>>();
 
     for include_glob in include_globs {
@@ -22,17 +38,194 @@ pub fn globby(
             require_literal_leading_dot: false,
         };
 
-        for entry in glob_with(&dir.join(&include_glob).to_string_lossy(), options).unwrap() {
-            if let Ok(path) = entry {
-                if !exclude_patterns
-                    .iter()
-                    .any(|exclude_pattern| exclude_pattern.matches_path(&path))
-                {
-                    matches.push(path);
+        let prefix = dir.join(literal_prefix(&include_glob));
+        let opted_in = prefix != dir
+            && ignores
+                .as_mut()
+                .is_some_and(|ignores| ignores.is_ignored(&prefix, prefix.is_dir()));
+
+        for path in glob_with(&dir.join(&include_glob).to_string_lossy(), options)
+            .unwrap()
+            .flatten()
+        {
+            if exclude_patterns
+                .iter()
+                .any(|exclude_pattern| exclude_pattern.matches_path(&path))
+            {
+                continue;
+            }
+            if let Some(ignores) = ignores.as_mut().filter(|_| !opted_in) {
+                if ignores.is_ignored(&path, path.is_dir()) {
+                    continue;
                 }
             }
+            matches.push(path);
         }
     }
 
     matches
 }
+
+/// Return the leading path components of a glob that don't contain any
+/// wildcards, like `src/generated` for `src/generated/**/*.ts`.
+fn literal_prefix(glob: &str) -> PathBuf {
+    Path::new(glob)
+        .components()
+        .take_while(|component| {
+            !component
+                .as_os_str()
+                .to_string_lossy()
+                .contains(['*', '?', '['])
+        })
+        .collect()
+}
+
+/// The ignore rules of a git repository: every `.gitignore` file, and
+/// `.git/info/exclude`. Each directory's `.gitignore` is read the first time
+/// a path inside it is checked.
+struct GitIgnores {
+    root: PathBuf,
+    exclude: Gitignore,
+    dirs: HashMap<PathBuf, Gitignore>,
+}
+
+impl GitIgnores {
+    /// Find the repository containing `dir`. Returns `None` outside of a git
+    /// repository, where nothing is ignored.
+    fn find(dir: &Path) -> Option<GitIgnores> {
+        let root = dir.ancestors().find(|dir| dir.join(".git").exists())?;
+        let mut exclude = GitignoreBuilder::new(root);
+        exclude.add(root.join(".git/info/exclude"));
+        Some(GitIgnores {
+            root: root.to_path_buf(),
+            exclude: exclude.build().unwrap_or_else(|_| Gitignore::empty()),
+            dirs: HashMap::new(),
+        })
+    }
+
+    /// Whether git ignores a path, or one of its parent directories. Like
+    /// git, a directory that's ignored ignores everything inside it: the
+    /// `.gitignore` files inside it can't re-include anything.
+    fn is_ignored(&mut self, path: &Path, is_dir: bool) -> bool {
+        let mut dirs = path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(&self.root))
+            .map(Path::to_path_buf)
+            .collect::<Vec<_>>();
+        dirs.reverse();
+        // Check each directory from the root down, against the rules of the
+        // directories above it.
+        for i in 1..dirs.len() {
+            if self.matches(&dirs[i], true, &dirs[..i]) {
+                return true;
+            }
+        }
+        self.matches(path, is_dir, &dirs)
+    }
+
+    /// Whether the `.gitignore` files in `dirs`, from the root down, or
+    /// `info/exclude` ignore a path. Deeper `.gitignore` files take
+    /// precedence, then `info/exclude`.
+    fn matches(&mut self, path: &Path, is_dir: bool, dirs: &[PathBuf]) -> bool {
+        for dir in dirs.iter().rev() {
+            let gitignore = self
+                .dirs
+                .entry(dir.clone())
+                .or_insert_with(|| Gitignore::new(dir.join(".gitignore")).0);
+            match gitignore.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        self.exclude.matched(path, is_dir).is_ignore()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::globby;
+    use crate::test_utils::test_dir;
+
+    fn relative_files(dir: &std::path::Path, matches: Vec<std::path::PathBuf>) -> Vec<String> {
+        let mut files = matches
+            .into_iter()
+            .filter(|path| path.is_file())
+            .map(|path| {
+                path.strip_prefix(dir)
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_excludes_are_relative_to_dir() {
+        let dir = test_dir("globby").join("dist");
+        for file in ["src/a.ts", "src/b.test.ts", "src/dist/c.ts", "dist/d.js"] {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+
+        let matches = globby(
+            &dir,
+            vec!["**/*".to_string()],
+            vec!["**/*.test.*".to_string(), "dist/**".to_string()],
+            false,
+        );
+        let matches = relative_files(&dir, matches);
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+
+        assert_eq!(matches, vec!["src/a.ts", "src/dist/c.ts"]);
+    }
+
+    #[test]
+    fn test_gitignore() {
+        let root = test_dir("gitignore");
+        let dir = root.join("packages/a");
+        for file in [
+            "src/index.ts",
+            "src/index.ts.swp",
+            "src/generated/schema.ts",
+            "src/assets/logo.svg",
+            "src/assets/keep.ts",
+            "src/vendor/lib.ts",
+            "src/vendor/keep.ts",
+        ] {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        fs::create_dir_all(root.join(".git/info")).unwrap();
+        fs::write(root.join(".git/info/exclude"), "*.swp\n").unwrap();
+        fs::write(root.join(".gitignore"), "generated/\nvendor/\n").unwrap();
+        fs::write(dir.join("src/assets/.gitignore"), "*\n!keep.ts\n").unwrap();
+        // The root ignores vendor/, so its own .gitignore can't re-include files
+        fs::write(dir.join("src/vendor/.gitignore"), "!keep.ts\n").unwrap();
+        let include = |globs: &[&str]| {
+            let globs = globs.iter().map(|s| s.to_string()).collect();
+            relative_files(&dir, globby(&dir, globs, vec![], true))
+        };
+
+        let files = include(&["src/**/*"]);
+        let opted_in = include(&["src/**/*", "src/generated/**/*"]);
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(files, vec!["src/assets/keep.ts", "src/index.ts"]);
+        assert_eq!(
+            opted_in,
+            vec![
+                "src/assets/keep.ts",
+                "src/generated/schema.ts",
+                "src/index.ts"
+            ]
+        );
+    }
+}
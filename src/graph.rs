@@ -5,24 +5,113 @@ use std::path::PathBuf;
 use std::rc::Rc;
 use std::{cell::RefCell, collections::HashMap};
 
-use crate::colors::{BOLD, CYAN, DIM, RESET};
+use crate::cache::{CacheFormat, RestoreStrategy};
+use crate::colors::{BOLD, CYAN, DIM, RESET, YELLOW};
 
 #[derive(Debug, Clone)]
 pub struct Package {
     pub dir: PathBuf,
     pub name: String,
-    pub build_script: Option<String>,
+    /// The package.json scripts, by name.
+    pub scripts: HashMap<String, String>,
     pub dependency_names: Vec<String>,
+    /// Which package.json sections each of `dependency_names` was listed in.
+    pub dependency_kinds: HashMap<String, Vec<DependencyKind>>,
     pub config: PackageConfig,
 }
 
+/// Commands scripts may run buildc through, like `npx buildc -- unbuild`.
+const PACKAGE_RUNNERS: &[&str] = &["npx", "bunx", "npm", "pnpm", "yarn", "bun", "exec", "x"];
+
 impl Package {
-    pub fn absolute_out_dir(self: &Self) -> PathBuf {
-        self.dir.join(self.config.out_dir.clone())
+    /// Return the command a package.json script is cached under, `None` when
+    /// the package doesn't have the script. Scripts like `buildc -- unbuild`
+    /// are cached under the command after `--`, the same key `buildc --
+    /// unbuild` uses when run directly, so both share one cache entry.
+    pub fn script_command(&self, script: &str) -> Option<String> {
+        let script = self.scripts.get(script)?;
+        let Some((prefix, command)) = script.split_once(" -- ") else {
+            return Some(script.clone());
+        };
+        let mut words = prefix.split_whitespace();
+        let is_buildc = words
+            .by_ref()
+            .find(|word| !PACKAGE_RUNNERS.contains(word))
+            .is_some_and(|word| word.rsplit('/').next() == Some("buildc"));
+        // Only options like `--concurrency 2` can come between buildc and `--`.
+        let has_options_only =
+            words.all(|word| word.starts_with('-') || word.parse::<usize>().is_ok());
+        if is_buildc && has_options_only {
+            Some(command.split_whitespace().collect::<Vec<_>>().join(" "))
+        } else {
+            Some(script.clone())
+        }
+    }
+}
+
+/// The package.json section a dependency is listed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DependencyKind {
+    Dependencies,
+    DevDependencies,
+    PeerDependencies,
+    OptionalDependencies,
+}
+
+impl DependencyKind {
+    pub const ALL: [DependencyKind; 4] = [
+        Self::Dependencies,
+        Self::DevDependencies,
+        Self::PeerDependencies,
+        Self::OptionalDependencies,
+    ];
+
+    /// The package.json field this kind of dependency is listed under.
+    pub fn field(&self) -> &'static str {
+        match self {
+            Self::Dependencies => "dependencies",
+            Self::DevDependencies => "devDependencies",
+            Self::PeerDependencies => "peerDependencies",
+            Self::OptionalDependencies => "optionalDependencies",
+        }
+    }
+
+    /// Short label shown in `buildc graph`.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Dependencies => "prod",
+            Self::DevDependencies => "dev",
+            Self::PeerDependencies => "peer",
+            Self::OptionalDependencies => "optional",
+        }
+    }
+
+    pub fn from_field(field: &str) -> Option<DependencyKind> {
+        Self::ALL.into_iter().find(|kind| kind.field() == field)
+    }
+}
+
+/// Which workspace dependencies a package inlines into its build output.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BundledDependencies {
+    None,
+    All,
+    Only(Vec<String>),
+}
+
+impl BundledDependencies {
+    pub fn includes(&self, dependency_name: &str) -> bool {
+        match self {
+            Self::None => false,
+            Self::All => true,
+            Self::Only(names) => names.iter().any(|name| name == dependency_name),
+        }
     }
 }
 
 const DEFAULT_CACHED: bool = true;
+const DEFAULT_RESTORE_STRATEGY: RestoreStrategy = RestoreStrategy::Auto;
+const DEFAULT_CACHE_FORMAT: CacheFormat = CacheFormat::Directory;
 const DEFAULT_OUT_DIR: &str = "dist";
 const DEFAULT_INCLUDE: &[&str] = &["src/**/*"];
 const DEFAULT_EXCLUDE: &[&str] = &[
@@ -38,23 +127,59 @@ const DEFAULT_EXCLUDE: &[&str] = &[
 pub struct PackageConfig {
     /// Whether the build output should be cached.
     pub cache: bool,
-    /// The directory where your build is output to, relative to package folder.
-    pub out_dir: String,
+    /// Directories, files or globs the build outputs, relative to package
+    /// folder. Defaults to `outDir`, the directory where your build is output to.
+    pub outputs: Vec<String>,
     /// List of glob patterns to include when checking if the package needs rebuilt.
     pub include: Vec<String>,
     /// List of glob patterns to ignore when checking if the package needs rebuilt.
     pub exclude: Vec<String>,
+    /// Which package.json dependency sections count as build dependencies.
+    pub dependency_kinds: Vec<DependencyKind>,
+    /// How outputs are copied into and restored from the cache.
+    pub restore_strategy: RestoreStrategy,
+    /// How outputs are stored in the cache, as files or a compressed archive.
+    pub cache_format: CacheFormat,
+    /// Names of environment variables read during the build, like `NODE_ENV`
+    /// or `VITE_*`. Their values are part of the package's hash.
+    pub env: Vec<String>,
+    /// Dependencies bundled into the output, whose hashes are part of the
+    /// package's hash so changing them rebuilds the package.
+    pub bundles_dependencies: BundledDependencies,
 }
 
 impl PackageConfig {
     fn default() -> PackageConfig {
         PackageConfig {
             cache: DEFAULT_CACHED,
-            out_dir: DEFAULT_OUT_DIR.to_string(),
+            outputs: vec![DEFAULT_OUT_DIR.to_string()],
             include: DEFAULT_INCLUDE.iter().map(|&s| s.to_string()).collect(),
             exclude: DEFAULT_EXCLUDE.iter().map(|&s| s.to_string()).collect(),
+            dependency_kinds: DependencyKind::ALL.to_vec(),
+            restore_strategy: DEFAULT_RESTORE_STRATEGY,
+            cache_format: DEFAULT_CACHE_FORMAT,
+            env: Vec::new(),
+            bundles_dependencies: BundledDependencies::None,
         }
     }
+
+    /// Parse a package's `buildc` block on top of the repo-wide defaults from
+    /// the root config. Keys set by the package replace the default entirely,
+    /// lists aren't concatenated. A package's `outDir` also replaces the
+    /// default `outputs`, since `outputs` would otherwise take precedence.
+    pub fn with_defaults(
+        defaults: &serde_json::Map<String, serde_json::Value>,
+        value: serde_json::Value,
+    ) -> PackageConfig {
+        let mut merged = defaults.clone();
+        if let serde_json::Value::Object(overrides) = value {
+            if overrides.contains_key("outDir") && !overrides.contains_key("outputs") {
+                merged.remove("outputs");
+            }
+            merged.extend(overrides);
+        }
+        PackageConfig::from(serde_json::Value::Object(merged))
+    }
 }
 
 impl From<serde_json::Value> for PackageConfig {
@@ -64,11 +189,21 @@ impl From<serde_json::Value> for PackageConfig {
                 .get("cache")
                 .and_then(|v| v.as_bool())
                 .unwrap_or(DEFAULT_CACHED),
-            out_dir: value
-                .get("outDir")
-                .and_then(|v| v.as_str())
-                .map(String::from)
-                .unwrap_or(DEFAULT_OUT_DIR.to_string()),
+            outputs: value
+                .get("outputs")
+                .and_then(|v| v.as_array())
+                .map(|arr| {
+                    arr.iter()
+                        .filter_map(|v| v.as_str().map(String::from))
+                        .collect()
+                })
+                .unwrap_or_else(|| {
+                    vec![value
+                        .get("outDir")
+                        .and_then(|v| v.as_str())
+                        .map(String::from)
+                        .unwrap_or(DEFAULT_OUT_DIR.to_string())]
+                }),
             include: value
                 .get("include")
                 .and_then(|v| v.as_array())
@@ -87,6 +222,43 @@ impl From<serde_json::Value> for PackageConfig {
                         .collect()
                 })
                 .unwrap_or_else(|| DEFAULT_EXCLUDE.iter().map(|&s| s.to_string()).collect()),
+            dependency_kinds: value
+                .get("dependencyKinds")
+                .and_then(|v| v.as_array())
+                .map(|arr| {
+                    arr.iter()
+                        .filter_map(|v| v.as_str().and_then(DependencyKind::from_field))
+                        .collect()
+                })
+                .unwrap_or_else(|| DependencyKind::ALL.to_vec()),
+            restore_strategy: value
+                .get("restoreStrategy")
+                .and_then(|v| v.as_str())
+                .and_then(RestoreStrategy::from_name)
+                .unwrap_or(DEFAULT_RESTORE_STRATEGY),
+            cache_format: value
+                .get("cacheFormat")
+                .and_then(|v| v.as_str())
+                .and_then(CacheFormat::from_name)
+                .unwrap_or(DEFAULT_CACHE_FORMAT),
+            env: value
+                .get("env")
+                .and_then(|v| v.as_array())
+                .map(|arr| {
+                    arr.iter()
+                        .filter_map(|v| v.as_str().map(String::from))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            bundles_dependencies: match value.get("bundlesDependencies") {
+                Some(serde_json::Value::Bool(true)) => BundledDependencies::All,
+                Some(serde_json::Value::Array(arr)) => BundledDependencies::Only(
+                    arr.iter()
+                        .filter_map(|v| v.as_str().map(String::from))
+                        .collect(),
+                ),
+                _ => BundledDependencies::None,
+            },
         }
     }
 }
@@ -139,35 +311,99 @@ impl Node {
         result.push(Rc::clone(node));
     }
 
+    /// Print the node and its children as a tree. `edge_kinds` are the kinds of
+    /// dependency the parent has on this node. `ancestors` holds the IDs of
+    /// the nodes above this one, so dependency cycles are printed once instead
+    /// of recursing forever.
     pub fn print<W: Write>(
         &self,
         writer: &mut W,
         depth: usize,
         is_last: bool,
+        edge_kinds: &[DependencyKind],
+        ancestors: &mut Vec<String>,
     ) -> std::io::Result<()> {
-        write!(
+        let is_cycle = ancestors.contains(&self.id);
+        writeln!(
             writer,
-            "{DIM}{}{}{RESET} {CYAN}{BOLD}{}{RESET}\n",
+            "{DIM}{}{}{RESET} {CYAN}{BOLD}{}{RESET}{}{}",
             "│ ".repeat(depth),
             if is_last { "└" } else { "├" },
             self.id,
+            if edge_kinds.is_empty() {
+                String::new()
+            } else {
+                let labels = edge_kinds
+                    .iter()
+                    .map(|kind| kind.label())
+                    .collect::<Vec<_>>();
+                format!(" {DIM}({}){RESET}", labels.join(", "))
+            },
+            if is_cycle {
+                format!(" {YELLOW}(cycle){RESET}")
+            } else {
+                String::new()
+            },
         )?;
+        if is_cycle {
+            return Ok(());
+        }
 
-        if self.children.len() > 0 {
+        ancestors.push(self.id.clone());
+        if !self.children.is_empty() {
             let last_index = self.children.len() - 1;
             for (index, child) in self.children.iter().enumerate() {
-                child
-                    .borrow()
-                    .print(writer, depth + 1, index == last_index)?;
+                let child = child.borrow();
+                let edge_kinds = self
+                    .package
+                    .dependency_kinds
+                    .get(&child.id)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default();
+                child.print(
+                    writer,
+                    depth + 1,
+                    index == last_index,
+                    edge_kinds,
+                    ancestors,
+                )?;
             }
         }
+        ancestors.pop();
         Ok(())
     }
 }
 
+/// A task to run in a package, like the `test` script of package `a`.
+#[derive(Debug, Clone)]
+pub struct TaskNode {
+    pub package: Package,
+    pub task: String,
+    /// IDs of the tasks that need to finish first, see `task_id`.
+    pub dependencies: Vec<String>,
+}
+
+impl TaskNode {
+    pub fn id(&self) -> String {
+        task_id(&self.package.name, &self.task)
+    }
+}
+
+/// The package.json script that builds a package. Only its outputs are cached.
+pub const BUILD_SCRIPT: &str = "build";
+
+/// Unique ID of a task in a package, like `a#test`.
+pub fn task_id(package_name: &str, task: &str) -> String {
+    format!("{package_name}#{task}")
+}
+
 pub struct Graph {
     pub root: Rc<RefCell<Node>>,
     node_map: HashMap<String, Rc<RefCell<Node>>>,
+    /// Dependency cycles in the graph, at least one for each group of packages
+    /// that depend on each other. Each cycle is a path of package names that
+    /// ends with the package it started from, like `["a", "b", "a"]`.
+    pub cycles: Vec<Vec<String>>,
 }
 
 impl Graph {
@@ -176,7 +412,8 @@ impl Graph {
             dir: PathBuf::new(),
             name: "root".to_string(),
             dependency_names: vec![],
-            build_script: None,
+            dependency_kinds: HashMap::new(),
+            scripts: HashMap::new(),
             config: PackageConfig::default(),
         });
 
@@ -218,7 +455,12 @@ impl Graph {
             }
         }
 
-        Graph { root, node_map }
+        let cycles = find_cycles(&root.borrow().children);
+        Graph {
+            root,
+            node_map,
+            cycles,
+        }
     }
 
     /// Return all packages in the graph in build order.
@@ -233,16 +475,112 @@ impl Graph {
 
     /// Return a package's dependencies in build order.
     pub fn get_package_dependencies_build_order(&self, package_name: &str) -> Option<Vec<Package>> {
-        match self.node_map.get(package_name) {
-            None => None,
-            Some(node) => Some(
-                node.borrow()
-                    .get_dependency_build_order()
-                    .iter()
-                    .map(|node| node.borrow().package.clone())
-                    .collect(),
-            ),
+        self.node_map.get(package_name).map(|node| {
+            node.borrow()
+                .get_dependency_build_order()
+                .iter()
+                .map(|node| node.borrow().package.clone())
+                .collect()
+        })
+    }
+
+    /// Return the dependencies a package bundles, directly or through the
+    /// dependencies it bundles, in build order.
+    pub fn get_bundled_dependencies_build_order(&self, package_name: &str) -> Vec<Package> {
+        let mut bundled = HashSet::new();
+        let mut queue = vec![package_name.to_string()];
+        while let Some(name) = queue.pop() {
+            let Some(package) = self.get_package(&name) else {
+                continue;
+            };
+            for dependency in &package.dependency_names {
+                if package.config.bundles_dependencies.includes(dependency)
+                    && bundled.insert(dependency.clone())
+                {
+                    queue.push(dependency.clone());
+                }
+            }
+        }
+
+        self.get_package_dependencies_build_order(package_name)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|package| bundled.contains(&package.name))
+            .collect()
+    }
+
+    /// Return the tasks needed to run the given root tasks (package name, task
+    /// name pairs), including the roots, in build order.
+    ///
+    /// `depends_on` returns the upstream tasks of a task: `^build` means the
+    /// `build` task of every dependency, `build` means the package's own
+    /// `build` task.
+    pub fn get_task_build_order(
+        &self,
+        roots: &[(String, String)],
+        depends_on: &dyn Fn(&str) -> Vec<String>,
+    ) -> Vec<TaskNode> {
+        let mut existence = HashSet::new();
+        let mut result = Vec::new();
+        for (package_name, task) in roots {
+            self.visit_task(package_name, task, depends_on, &mut existence, &mut result);
         }
+        result
+    }
+
+    fn visit_task(
+        &self,
+        package_name: &str,
+        task: &str,
+        depends_on: &dyn Fn(&str) -> Vec<String>,
+        existence: &mut HashSet<String>,
+        result: &mut Vec<TaskNode>,
+    ) {
+        let Some(package) = self.get_package(package_name) else {
+            return;
+        };
+        if !existence.insert(task_id(package_name, task)) {
+            return;
+        }
+
+        let mut upstream = Vec::new();
+        for dependency in depends_on(task) {
+            match dependency.strip_prefix('^') {
+                Some(dependency_task) => {
+                    for dependency_name in &package.dependency_names {
+                        if self.node_map.contains_key(dependency_name) {
+                            upstream.push((dependency_name.clone(), dependency_task.to_string()));
+                        }
+                    }
+                }
+                None => upstream.push((package_name.to_string(), dependency)),
+            }
+        }
+
+        for (upstream_package, upstream_task) in &upstream {
+            self.visit_task(
+                upstream_package,
+                upstream_task,
+                depends_on,
+                existence,
+                result,
+            );
+        }
+        result.push(TaskNode {
+            package,
+            task: task.to_string(),
+            dependencies: upstream
+                .iter()
+                .map(|(package_name, task)| task_id(package_name, task))
+                .collect(),
+        });
+    }
+
+    /// Return the package with the given name.
+    pub fn get_package(&self, package_name: &str) -> Option<Package> {
+        self.node_map
+            .get(package_name)
+            .map(|node| node.borrow().package.clone())
     }
 
     /// Based off your CWD, return the package that you are inside.
@@ -262,34 +600,121 @@ impl Graph {
     pub fn print(&self) -> std::io::Result<()> {
         println!("Dependency Graph:");
         let mut stdout = std::io::stdout().lock();
-        let last_index = self.root.borrow().children.len() - 1;
-        for (index, package_node) in self.root.borrow().children.clone().iter().enumerate() {
-            package_node
-                .borrow()
-                .print(&mut stdout, 0, index == last_index)?;
+        let children = self.root.borrow().children.clone();
+        for (index, package_node) in children.iter().enumerate() {
+            package_node.borrow().print(
+                &mut stdout,
+                0,
+                index == children.len() - 1,
+                &[],
+                &mut Vec::new(),
+            )?;
         }
         Ok(())
     }
 }
 
+/// Format a cycle returned by `Graph::cycles` for printing, like `a → b → a`.
+pub fn format_cycle(cycle: &[String]) -> String {
+    cycle.join(" → ")
+}
+
+/// Find dependency cycles reachable from the given nodes: at least one cycle
+/// per strongly connected component, not every cycle. Each package is only
+/// visited once, so cycles sharing an already visited package can be missed.
+fn find_cycles(nodes: &[Rc<RefCell<Node>>]) -> Vec<Vec<String>> {
+    let mut visited = HashSet::new();
+    let mut path = Vec::new();
+    let mut cycles = Vec::new();
+    for node in nodes {
+        visit_for_cycles(node, &mut visited, &mut path, &mut cycles);
+    }
+    cycles
+}
+
+fn visit_for_cycles(
+    node: &Rc<RefCell<Node>>,
+    visited: &mut HashSet<String>,
+    path: &mut Vec<String>,
+    cycles: &mut Vec<Vec<String>>,
+) {
+    let id = node.borrow().id.clone();
+    if let Some(start) = path.iter().position(|ancestor| *ancestor == id) {
+        let mut cycle = path[start..].to_vec();
+        cycle.push(id);
+        if !cycles
+            .iter()
+            .any(|existing| is_same_cycle(existing, &cycle))
+        {
+            cycles.push(cycle);
+        }
+        return;
+    }
+    if !visited.insert(id.clone()) {
+        return;
+    }
+
+    path.push(id);
+    for child in &node.borrow().children {
+        visit_for_cycles(child, visited, path, cycles);
+    }
+    path.pop();
+}
+
+/// Cycles are equal when they contain the same edges, regardless of which
+/// package they start from.
+fn is_same_cycle(a: &[String], b: &[String]) -> bool {
+    let a = &a[..a.len() - 1];
+    let b = &b[..b.len() - 1];
+    a.len() == b.len()
+        && (0..a.len()).any(|offset| (0..a.len()).all(|i| a[(i + offset) % a.len()] == b[i]))
+}
+
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
     use std::path::PathBuf;
 
-    use crate::graph::{Graph, Package};
+    use crate::graph::{format_cycle, Graph, Package};
 
-    use super::PackageConfig;
+    use super::{BundledDependencies, PackageConfig};
 
     fn test_package(name: &str, dependency_names: Vec<&str>) -> Package {
         Package {
             dir: PathBuf::new(),
             name: name.to_string(),
             dependency_names: dependency_names.iter().map(|str| str.to_string()).collect(),
-            build_script: None,
+            dependency_kinds: HashMap::new(),
+            scripts: HashMap::new(),
             config: PackageConfig::default(),
         }
     }
 
+    #[test]
+    fn test_config_defaults() {
+        let defaults = serde_json::json!({ "outDir": "lib", "include": ["lib-src/**/*"] });
+        let defaults = defaults.as_object().unwrap();
+
+        let config = PackageConfig::with_defaults(defaults, serde_json::Value::Null);
+        assert_eq!(config.outputs, vec!["lib"]);
+        assert_eq!(config.include, vec!["lib-src/**/*"]);
+        assert!(config.cache);
+
+        let config = PackageConfig::with_defaults(
+            defaults,
+            serde_json::json!({ "outDir": "build", "cache": false }),
+        );
+        assert_eq!(config.outputs, vec!["build"]);
+        assert_eq!(config.include, vec!["lib-src/**/*"]);
+        assert!(!config.cache);
+
+        let defaults = serde_json::json!({ "outputs": ["lib", "types"] });
+        let defaults = defaults.as_object().unwrap();
+        let config =
+            PackageConfig::with_defaults(defaults, serde_json::json!({ "outDir": "build" }));
+        assert_eq!(config.outputs, vec!["build"]);
+    }
+
     #[test]
     fn test_dependency_graph() {
         let a = test_package("a", vec!["b", "c"]);
@@ -324,5 +749,96 @@ mod tests {
             c_order.iter().map(|p| p.name.clone()).collect::<Vec<_>>(),
             Vec::<String>::new()
         );
+
+        assert!(graph.cycles.is_empty());
+    }
+
+    #[test]
+    fn test_dependency_cycles() {
+        let a = test_package("a", vec!["b"]);
+        let b = test_package("b", vec!["c"]);
+        let c = test_package("c", vec!["a"]);
+        let d = test_package("d", vec!["d", "a"]);
+        let graph = Graph::new(vec![a, b, c, d]);
+
+        assert_eq!(graph.cycles, vec![vec!["a", "b", "c", "a"], vec!["d", "d"]]);
+        assert_eq!(format_cycle(&graph.cycles[0]), "a → b → c → a");
+    }
+
+    #[test]
+    fn test_task_graph() {
+        let a = test_package("a", vec!["b"]);
+        let b = test_package("b", vec![]);
+        let graph = Graph::new(vec![a, b]);
+        let depends_on = |task: &str| match task {
+            "test" => vec!["^build".to_string(), "build".to_string()],
+            task => vec![format!("^{task}")],
+        };
+
+        let order =
+            graph.get_task_build_order(&[("a".to_string(), "test".to_string())], &depends_on);
+        assert_eq!(
+            order.iter().map(|node| node.id()).collect::<Vec<_>>(),
+            vec!["b#build", "a#build", "a#test"]
+        );
+        assert_eq!(order[2].dependencies, vec!["b#build", "a#build"]);
+        assert_eq!(order[1].dependencies, vec!["b#build"]);
+    }
+
+    #[test]
+    fn test_bundled_dependencies() {
+        // app bundles ui, which bundles icons but not utils
+        let mut app = test_package("app", vec!["ui", "api"]);
+        app.config.bundles_dependencies = BundledDependencies::Only(vec!["ui".to_string()]);
+        let mut ui = test_package("ui", vec!["icons", "utils"]);
+        ui.config.bundles_dependencies = BundledDependencies::Only(vec!["icons".to_string()]);
+        let icons = test_package("icons", vec![]);
+        let utils = test_package("utils", vec![]);
+        let api = test_package("api", vec![]);
+        let graph = Graph::new(vec![app, ui, icons, utils, api]);
+
+        let names = |packages: Vec<Package>| {
+            packages
+                .into_iter()
+                .map(|package| package.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(graph.get_bundled_dependencies_build_order("app")),
+            vec!["icons", "ui"]
+        );
+        assert_eq!(
+            names(graph.get_bundled_dependencies_build_order("ui")),
+            vec!["icons"]
+        );
+        assert!(graph.get_bundled_dependencies_build_order("api").is_empty());
+    }
+
+    #[test]
+    fn test_script_command() {
+        let mut package = test_package("a", vec![]);
+        for (name, script) in [
+            ("direct", "buildc -- unbuild"),
+            ("runner", "npx buildc --concurrency 2 -- tsup  --minify"),
+            ("plain", "tsup -- --minify"),
+            ("subcommand", "buildc deps -- vitest"),
+            ("chained", "tsc && buildc -- unbuild"),
+        ] {
+            package.scripts.insert(name.to_string(), script.to_string());
+        }
+
+        let command = |script| package.script_command(script);
+        assert_eq!(command("direct").as_deref(), Some("unbuild"));
+        assert_eq!(command("runner").as_deref(), Some("tsup --minify"));
+        assert_eq!(command("plain").as_deref(), Some("tsup -- --minify"));
+        assert_eq!(
+            command("subcommand").as_deref(),
+            Some("buildc deps -- vitest")
+        );
+        assert_eq!(
+            command("chained").as_deref(),
+            Some("tsc && buildc -- unbuild")
+        );
+        assert_eq!(command("missing"), None);
     }
 }
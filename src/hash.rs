@@ -1,43 +1,565 @@
-use md5;
-use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::SystemTime;
 
+use glob::Pattern;
+
+use crate::cache::CACHE_VERSION;
+use crate::ctx::Ctx;
 use crate::globby::globby;
-use crate::graph::Package;
+use crate::graph::{DependencyKind, Package, BUILD_SCRIPT};
+use crate::memo::{FileStat, HashMemo, MEMO_FILE};
+use crate::monorepo::Monorepo;
+
+/// package.json fields that can change a package's build output. Scripts are
+/// left out, only the command being run is hashed.
+const HASHED_PACKAGE_JSON_FIELDS: &[&str] = &[
+    "name",
+    "version",
+    "type",
+    "main",
+    "module",
+    "types",
+    "exports",
+    "imports",
+    "bin",
+    "sideEffects",
+    "browserslist",
+    "dependencies",
+    "devDependencies",
+    "peerDependencies",
+    "optionalDependencies",
+    "buildc",
+];
+
+/// Everything a package's hash is computed from. Environment variables only
+/// keep a hash of their value, keyed with the cache's secret key, so inputs can
+/// be saved, printed and shared without leaking secrets.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PackageInputs {
+    /// Hash of each input file, by path relative to the package directory.
+    pub files: Vec<(String, String)>,
+    /// Hash of each global input file, by path relative to the workspace root.
+    pub global_files: Vec<(String, String)>,
+    /// Hash of the package.json fields in `HASHED_PACKAGE_JSON_FIELDS`.
+    pub package_json: String,
+    /// Hash of the package's slice of the lockfile.
+    pub lockfile: String,
+    /// Keyed hash of each environment variable's value, `None` when it's
+    /// unset.
+    pub env: Vec<(String, Option<String>)>,
+    /// Identifies the key `env` was hashed with. Hashes made with different
+    /// keys can't be compared, only whether each variable is set.
+    pub env_key_id: String,
+    /// Final hash of each bundled dependency, by name.
+    pub bundled: Vec<(String, String)>,
+    pub command: String,
+}
+
+/// A difference between two sets of inputs, see `PackageInputs::diff`.
+#[derive(Debug, PartialEq)]
+pub enum InputChange {
+    Added(String),
+    Removed(String),
+    Changed(String),
+}
+
+impl PackageInputs {
+    /// Return a line per input, the text the package hash is computed from.
+    /// Environment variables only list their name.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![format!("version-{CACHE_VERSION}")];
+        for (path, hash) in &self.files {
+            lines.push(format!("{hash}-{path}"));
+        }
+        for (path, hash) in &self.global_files {
+            lines.push(format!("global-{hash}-{path}"));
+        }
+        lines.push(format!("package.json-{}", self.package_json));
+        lines.push(format!("lockfile-{}", self.lockfile));
+        for (name, _) in &self.env {
+            lines.push(format!("env-{name}"));
+        }
+        for (name, hash) in &self.bundled {
+            lines.push(format!("bundled-{hash}-{name}"));
+        }
+        lines.push(format!("command-{}", self.command));
+        lines
+    }
+
+    /// Return what changed since `previous`, with each input described like
+    /// `src/index.ts` or `env NODE_ENV`.
+    pub fn diff(&self, previous: &PackageInputs) -> Vec<InputChange> {
+        let mut changes = diff_named("", &self.files, &previous.files);
+        changes.extend(diff_named(
+            "global input ",
+            &self.global_files,
+            &previous.global_files,
+        ));
+        if self.package_json != previous.package_json {
+            changes.push(InputChange::Changed("package.json".to_string()));
+        }
+        if self.lockfile != previous.lockfile {
+            changes.push(InputChange::Changed("lockfile".to_string()));
+        }
+        if self.env_key_id == previous.env_key_id {
+            changes.extend(diff_named("env ", &self.env, &previous.env));
+        } else {
+            let is_set = |env: &[(String, Option<String>)]| {
+                env.iter()
+                    .map(|(name, hash)| (name.clone(), hash.is_some()))
+                    .collect::<Vec<_>>()
+            };
+            changes.extend(diff_named(
+                "env ",
+                &is_set(&self.env),
+                &is_set(&previous.env),
+            ));
+        }
+        changes.extend(diff_named(
+            "bundled dependency ",
+            &self.bundled,
+            &previous.bundled,
+        ));
+        if self.command != previous.command {
+            changes.push(InputChange::Changed(format!(
+                "command, was {:?}",
+                previous.command
+            )));
+        }
+        changes
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        fn object<T: Clone + Into<serde_json::Value>>(items: &[(String, T)]) -> serde_json::Value {
+            items
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone().into()))
+                .collect::<serde_json::Map<_, _>>()
+                .into()
+        }
+        serde_json::json!({
+            "version": CACHE_VERSION,
+            "files": object(&self.files),
+            "globalFiles": object(&self.global_files),
+            "packageJson": self.package_json,
+            "lockfile": self.lockfile,
+            "env": object(&self.env),
+            "envKeyId": self.env_key_id,
+            "bundledDependencies": object(&self.bundled),
+            "command": self.command,
+        })
+    }
+
+    /// Parse inputs saved with `to_json`. Returns `None` for invalid or
+    /// outdated inputs.
+    pub fn from_json(json: &serde_json::Value) -> Option<PackageInputs> {
+        fn entries(value: &serde_json::Value) -> Option<Vec<(String, String)>> {
+            value
+                .as_object()?
+                .iter()
+                .map(|(name, hash)| Some((name.clone(), hash.as_str()?.to_string())))
+                .collect()
+        }
+        if json["version"].as_u64() != Some(CACHE_VERSION.into()) {
+            return None;
+        }
+        Some(PackageInputs {
+            files: entries(&json["files"])?,
+            global_files: entries(&json["globalFiles"])?,
+            package_json: json["packageJson"].as_str()?.to_string(),
+            lockfile: json["lockfile"].as_str()?.to_string(),
+            env: json["env"]
+                .as_object()?
+                .iter()
+                .map(|(name, hash)| (name.clone(), hash.as_str().map(str::to_string)))
+                .collect(),
+            env_key_id: json["envKeyId"].as_str()?.to_string(),
+            bundled: entries(&json["bundledDependencies"])?,
+            command: json["command"].as_str()?.to_string(),
+        })
+    }
+}
+
+/// Compare two lists of named values, like files and their hashes.
+fn diff_named<T: PartialEq>(
+    prefix: &str,
+    current: &[(String, T)],
+    previous: &[(String, T)],
+) -> Vec<InputChange> {
+    let previous = previous
+        .iter()
+        .map(|(name, value)| (name, value))
+        .collect::<BTreeMap<_, _>>();
+    let current = current
+        .iter()
+        .map(|(name, value)| (name, value))
+        .collect::<BTreeMap<_, _>>();
+    let mut changes = Vec::new();
+    for (name, value) in &current {
+        match previous.get(name) {
+            None => changes.push(InputChange::Added(format!("{prefix}{name}"))),
+            Some(previous) if previous != value => {
+                changes.push(InputChange::Changed(format!("{prefix}{name}")))
+            }
+            Some(_) => {}
+        }
+    }
+    for name in previous.keys() {
+        if !current.contains_key(name) {
+            changes.push(InputChange::Removed(format!("{prefix}{name}")));
+        }
+    }
+    changes
+}
 
-pub fn hash_package(package: &Package) -> std::io::Result<(String, String)> {
+/// Hash a package's inputs: the files matching its config that git doesn't
+/// ignore, the repo's global input files, the relevant package.json fields,
+/// its dependencies' versions from the lockfile, the declared environment
+/// variables, and the command used to build it, and the final hashes of the
+/// dependencies it bundles. Returns the hash and the inputs used to compute
+/// it.
+///
+/// Environment variables can hold secrets, so their lines only list the
+/// variable's name. Values are mixed into the hash separately.
+///
+/// File hashes are memoized in the package's cache directory, so only files
+/// whose size, mtime or inode changed since the last call are read again.
+/// Hashing shares the CPUs with the other `ctx.concurrency` packages being
+/// built.
+pub fn hash_package(
+    ctx: &Ctx,
+    monorepo: &Monorepo,
+    package: &Package,
+    command: &str,
+    bundled_hashes: &[(String, String)],
+) -> std::io::Result<(String, PackageInputs)> {
     let mut files = globby(
         &package.dir,
         package.config.include.clone(),
         package.config.exclude.clone(),
+        true,
     );
     files.sort();
 
-    let dir_hash = files
+    let memo_path = monorepo.cache_dir().join(&package.name).join(MEMO_FILE);
+    let memo = HashMemo::load(&memo_path);
+    let threads = hash_threads(ctx);
+    let (file_hashes, new_memo) = hash_files(&package.dir, &files, &memo, threads)?;
+    if new_memo != memo {
+        new_memo.save(&memo_path, &package.dir)?;
+    }
+
+    let mut global_files = globby(
+        &monorepo.root,
+        monorepo.config.global_inputs.clone(),
+        vec![],
+        true,
+    );
+    global_files.sort();
+    let (global_file_hashes, _) =
+        hash_files(&monorepo.root, &global_files, &HashMemo::default(), threads)?;
+
+    let package_json: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(package.dir.join("package.json"))?)?;
+    let fields = HASHED_PACKAGE_JSON_FIELDS
+        .iter()
+        .filter_map(|&field| Some((field.to_string(), package_json.get(field)?.clone())))
+        .collect::<serde_json::Map<_, _>>();
+    let fields = serde_json::to_string(&fields)?;
+
+    let dependencies = DependencyKind::ALL
+        .iter()
+        .filter_map(|kind| package_json[kind.field()].as_object())
+        .flatten()
+        .filter_map(|(name, spec)| Some((name.clone(), spec.as_str()?.to_string())))
+        .collect::<Vec<_>>();
+    let package_dir = package
+        .dir
+        .strip_prefix(&monorepo.root)
+        .unwrap_or(&package.dir)
+        .to_string_lossy()
+        .replace('\\', "/");
+    let lockfile_slice = monorepo
+        .lockfile()
+        .package_slice(&package_dir, &dependencies);
+
+    let env_vars = env_inputs(monorepo.config.env.iter().chain(package.config.env.iter()));
+    let mut env_values = String::new();
+    for (name, value) in &env_vars {
+        match value {
+            Some(value) => env_values.push_str(&format!("\n{name}={value}")),
+            None => env_values.push_str(&format!("\n{name} unset")),
+        }
+    }
+
+    let inputs_key = monorepo.inputs_key();
+    let inputs = PackageInputs {
+        files: file_hashes,
+        global_files: global_file_hashes,
+        package_json: blake3::hash(fields.as_bytes()).to_string(),
+        lockfile: blake3::hash(lockfile_slice.as_bytes()).to_string(),
+        env: env_vars
+            .iter()
+            .map(|(name, value)| {
+                let hash = value
+                    .as_ref()
+                    .map(|value| blake3::keyed_hash(inputs_key, value.as_bytes()).to_string());
+                (name.clone(), hash)
+            })
+            .collect(),
+        env_key_id: blake3::hash(inputs_key).to_hex()[..16].to_string(),
+        bundled: bundled_hashes.to_vec(),
+        command: command.to_string(),
+    };
+
+    let lines = inputs.lines().join("\n");
+    let digest = blake3::hash(format!("{lines}{env_values}").as_bytes());
+    Ok((digest.to_hex().to_string(), inputs))
+}
+
+/// Return the final hash of each dependency a package bundles, by name.
+/// `bundled_dependencies` must be in build order, see
+/// `Graph::get_bundled_dependencies_build_order`, so each dependency's hash
+/// can include the hashes of the dependencies it bundles itself.
+pub fn bundled_dependency_hashes(
+    ctx: &Ctx,
+    monorepo: &Monorepo,
+    package: &Package,
+    bundled_dependencies: &[Package],
+) -> std::io::Result<Vec<(String, String)>> {
+    let mut hashes = BTreeMap::new();
+    for dependency in bundled_dependencies {
+        let command = dependency.script_command(BUILD_SCRIPT).unwrap_or_default();
+        let (hash, _) = hash_package(
+            ctx,
+            monorepo,
+            dependency,
+            &command,
+            &bundled_hashes(dependency, &hashes),
+        )?;
+        hashes.insert(dependency.name.clone(), hash);
+    }
+    Ok(bundled_hashes(package, &hashes))
+}
+
+fn bundled_hashes(package: &Package, hashes: &BTreeMap<String, String>) -> Vec<(String, String)> {
+    package
+        .dependency_names
+        .iter()
+        .filter(|name| package.config.bundles_dependencies.includes(name))
+        .filter_map(|name| Some((name.clone(), hashes.get(name)?.clone())))
+        .collect()
+}
+
+/// Return the environment variables matching the names, sorted by name.
+/// Names can contain wildcards, like `VITE_*`, which only match variables
+/// that are set. Exact names are returned even when they're unset.
+fn env_inputs<'a>(names: impl Iterator<Item = &'a String>) -> BTreeMap<String, Option<String>> {
+    let mut vars = BTreeMap::new();
+    for name in names {
+        if Pattern::escape(name) == *name {
+            vars.insert(name.clone(), env::var(name).ok());
+            continue;
+        }
+        let Ok(pattern) = Pattern::new(name) else {
+            continue;
+        };
+        for (key, value) in env::vars() {
+            if pattern.matches(&key) {
+                vars.insert(key, Some(value));
+            }
+        }
+    }
+    vars
+}
+
+/// Number of threads each package can hash its files with, so that hashing
+/// `ctx.concurrency` packages at once uses about one thread per CPU.
+fn hash_threads(ctx: &Ctx) -> usize {
+    let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+    (cpus / ctx.concurrency.max(1)).max(1)
+}
+
+/// Return the path and hash of each file, with paths relative to `dir`. Files
+/// are split between up to `threads` threads and hashed in parallel, and are
+/// returned in the same order they were passed in.
+///
+/// Files whose stat data matches `memo` reuse the memoized hash. Also returns
+/// the updated memo, holding only the files passed in.
+fn hash_files(
+    dir: &Path,
+    files: &[PathBuf],
+    memo: &HashMemo,
+    threads: usize,
+) -> std::io::Result<(Vec<(String, String)>, HashMemo)> {
+    let files = files
         .iter()
         .filter(|file| file.is_file())
-        .map(|file| {
-            let hash = hash_file(file).expect("Failed to hash file");
-            let relative_path = file
-                .strip_prefix(&package.dir.clone())
-                .unwrap_or(file)
-                .to_string_lossy();
-            format!("{hash}-{relative_path}")
-        })
-        .collect::<Vec<String>>()
-        .join("\n");
+        .collect::<Vec<_>>();
+    let chunk_size = files.len().div_ceil(threads.max(1)).max(1);
+    // Taken before any file is read, so a file written while hashing is
+    // always considered too recent to memoize.
+    let hashed_at = SystemTime::now();
+
+    thread::scope(|scope| {
+        let handles = files
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|file| {
+                            let relative_path = file
+                                .strip_prefix(dir)
+                                .unwrap_or(file)
+                                .to_string_lossy()
+                                .to_string();
+                            let stat = FileStat::new(&fs::metadata(file)?);
+                            let hash = match memo.get(&relative_path, &stat) {
+                                Some(hash) => hash.to_string(),
+                                None => hash_file(file)?,
+                            };
+                            Ok((relative_path, stat, hash))
+                        })
+                        .collect::<std::io::Result<Vec<_>>>()
+                })
+            })
+            .collect::<Vec<_>>();
 
-    let digest = md5::compute(&dir_hash);
-    let digest_str = format!("{:x}", digest);
-    Ok((digest_str, dir_hash))
+        let mut hashes = Vec::with_capacity(files.len());
+        let mut new_memo = HashMemo::default();
+        for handle in handles {
+            for (relative_path, stat, hash) in handle.join().unwrap()? {
+                new_memo.insert(relative_path.clone(), stat, hash.clone(), hashed_at);
+                hashes.push((relative_path, hash));
+            }
+        }
+        Ok((hashes, new_memo))
+    })
 }
 
-fn hash_file(file: &PathBuf) -> std::io::Result<String> {
-    let mut file = File::open(file).expect("Failed to open file");
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).expect("Failed to read file");
+/// Hash a file's contents, reading it in chunks instead of all at once.
+pub fn hash_file(file: &Path) -> std::io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(File::open(file)?)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::time::{Duration, SystemTime};
+
+    use super::{env_inputs, hash_files, InputChange, PackageInputs};
+    use crate::memo::HashMemo;
+    use crate::test_utils::test_dir;
+
+    #[test]
+    fn test_env_inputs() {
+        env::set_var("BUILDC_TEST_VITE_A", "a");
+        env::set_var("BUILDC_TEST_VITE_B", "b");
+        env::set_var("BUILDC_TEST_NODE_ENV", "production");
+        let names = [
+            "BUILDC_TEST_NODE_ENV".to_string(),
+            "BUILDC_TEST_VITE_*".to_string(),
+            "BUILDC_TEST_UNSET".to_string(),
+        ];
+
+        let vars = env_inputs(names.iter()).into_iter().collect::<Vec<_>>();
+        assert_eq!(
+            vars,
+            vec![
+                (
+                    "BUILDC_TEST_NODE_ENV".to_string(),
+                    Some("production".to_string())
+                ),
+                ("BUILDC_TEST_UNSET".to_string(), None),
+                ("BUILDC_TEST_VITE_A".to_string(), Some("a".to_string())),
+                ("BUILDC_TEST_VITE_B".to_string(), Some("b".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_memoized_file_hashes() {
+        let dir = test_dir("memo");
+        let old = dir.join("old.ts");
+        let recent = dir.join("recent.ts");
+        fs::write(&old, "aaaa").unwrap();
+        fs::write(&recent, "bbbb").unwrap();
+        let mtime = SystemTime::now() - Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(&old)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        let files = vec![old.clone(), recent.clone()];
+
+        let (hashes, memo) = hash_files(&dir, &files, &HashMemo::default(), 4).unwrap();
+        // Same size and mtime, so the memoized hash is used without reading
+        // the file. The recent file was too new to memoize and is read again.
+        fs::write(&old, "cccc").unwrap();
+        fs::write(&recent, "dddd").unwrap();
+        File::options()
+            .write(true)
+            .open(&old)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        let (memoized_hashes, _) = hash_files(&dir, &files, &memo, 4).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(memoized_hashes[0], hashes[0]);
+        assert_ne!(memoized_hashes[1], hashes[1]);
+    }
+
+    #[test]
+    fn test_inputs_diff() {
+        let previous = PackageInputs {
+            files: vec![
+                ("src/a.ts".to_string(), "1".to_string()),
+                ("src/b.ts".to_string(), "2".to_string()),
+            ],
+            env: vec![("NODE_ENV".to_string(), Some("3".to_string()))],
+            command: "tsup".to_string(),
+            ..Default::default()
+        };
+        let current = PackageInputs {
+            files: vec![
+                ("src/a.ts".to_string(), "4".to_string()),
+                ("src/c.ts".to_string(), "5".to_string()),
+            ],
+            env: vec![("NODE_ENV".to_string(), None)],
+            command: "tsup --minify".to_string(),
+            ..previous.clone()
+        };
+        let saved = PackageInputs::from_json(&previous.to_json()).unwrap();
 
-    let digest = md5::compute(&buffer);
-    Ok(format!("{:x}", digest))
+        assert_eq!(saved, previous);
+        assert_eq!(
+            PackageInputs {
+                env: vec![("NODE_ENV".to_string(), Some("6".to_string()))],
+                env_key_id: "other".to_string(),
+                ..previous.clone()
+            }
+            .diff(&saved),
+            vec![]
+        );
+        assert_eq!(
+            current.diff(&saved),
+            vec![
+                InputChange::Changed("src/a.ts".to_string()),
+                InputChange::Added("src/c.ts".to_string()),
+                InputChange::Removed("src/b.ts".to_string()),
+                InputChange::Changed("env NODE_ENV".to_string()),
+                InputChange::Changed("command, was \"tsup\"".to_string()),
+            ]
+        );
+    }
 }
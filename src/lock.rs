@@ -0,0 +1,86 @@
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::colors::{DIM, RESET, YELLOW};
+use crate::ctx::Ctx;
+
+/// Name of the lock file inside a package's cache directory.
+const LOCK_FILE: &str = ".lock";
+
+/// An advisory, cross-process lock on a single package's cache directory,
+/// released when dropped.
+///
+/// The OS releases the underlying file lock when the holder exits, even if it
+/// crashed, so a lock can never be held by a dead process. The holder's PID is
+/// written into the file so waiting processes can say who they're waiting on,
+/// and it's cleared on a clean release. Finding a PID in a lock file that could
+/// be acquired immediately means the previous holder didn't exit cleanly.
+pub struct PackageLock {
+    file: File,
+}
+
+impl PackageLock {
+    /// Block until this process holds the lock for a package's cache directory.
+    pub fn acquire(
+        ctx: &Ctx,
+        package_cache_dir: &Path,
+        package_name: &str,
+    ) -> std::io::Result<PackageLock> {
+        fs::create_dir_all(package_cache_dir)?;
+        let path = package_cache_dir.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        match file.try_lock() {
+            Ok(()) => {
+                if let Some(pid) = read_pid(&mut file) {
+                    println!("{YELLOW}[buildc] !{RESET} {package_name}: Recovered stale lock left by PID {pid}");
+                }
+            }
+            Err(TryLockError::WouldBlock) => {
+                match read_pid(&mut file) {
+                    Some(pid) => println!(
+                        "{YELLOW}[buildc] …{RESET} {package_name}: Waiting for lock held by PID {pid}"
+                    ),
+                    None => println!(
+                        "{YELLOW}[buildc] …{RESET} {package_name}: Waiting for lock held by another process"
+                    ),
+                }
+                file.lock()?;
+            }
+            Err(TryLockError::Error(err)) => return Err(err),
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", std::process::id())?;
+        file.flush()?;
+
+        if ctx.is_debug {
+            println!("{DIM}[buildc] → Acquired lock {path:?}{RESET}");
+        }
+        Ok(PackageLock { file })
+    }
+}
+
+impl Drop for PackageLock {
+    fn drop(&mut self) {
+        // Clear the PID before unlocking so the next holder doesn't mistake
+        // this for a lock left behind by a crashed process.
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+    }
+}
+
+/// Read the PID of the process that last wrote the lock file, if any.
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut content = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut content).ok()?;
+    content.trim().parse().ok()
+}
@@ -0,0 +1,421 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::hash::Hash;
+use std::path::Path;
+
+/// The workspace lockfile, parsed just enough to find the versions each
+/// package's dependencies resolved to.
+pub enum Lockfile {
+    /// `package-lock.json`
+    Npm(serde_json::Value),
+    /// `pnpm-lock.yaml`
+    Pnpm(serde_yaml::Value),
+    /// `yarn.lock`, v1 or Berry. Maps each descriptor, like `react@^18.0.0`,
+    /// to the package it resolved to.
+    Yarn(HashMap<String, YarnEntry>),
+    /// A lockfile that isn't parsed, like Bun's, represented by the hash of
+    /// its contents.
+    Other(String),
+    None,
+}
+
+impl Lockfile {
+    pub fn read(root: &Path) -> Lockfile {
+        let read = |file: &str| fs::read_to_string(root.join(file)).ok();
+
+        if let Some(content) = read("pnpm-lock.yaml") {
+            if let Ok(yaml) = serde_yaml::from_str(&content) {
+                return Lockfile::Pnpm(yaml);
+            }
+        }
+        if let Some(content) = read("package-lock.json").or_else(|| read("npm-shrinkwrap.json")) {
+            if let Ok(json) = serde_json::from_str(&content) {
+                return Lockfile::Npm(json);
+            }
+        }
+        if let Some(content) = read("yarn.lock") {
+            return Lockfile::Yarn(parse_yarn_lock(&content));
+        }
+        for file in ["bun.lock", "bun.lockb"] {
+            if let Ok(content) = fs::read(root.join(file)) {
+                return Lockfile::Other(blake3::hash(&content).to_hex().to_string());
+            }
+        }
+        Lockfile::None
+    }
+
+    /// Return the part of the lockfile describing a package's dependencies, as
+    /// text to include in its hash. `package_dir` is relative to the workspace
+    /// root, and `dependencies` are the names and version specifiers from the
+    /// package.json.
+    ///
+    /// Dependencies are followed transitively, so updating a dependency of a
+    /// dependency changes the slice too. Lockfiles that aren't parsed are
+    /// included entirely.
+    pub fn package_slice(&self, package_dir: &str, dependencies: &[(String, String)]) -> String {
+        let lines = match self {
+            Lockfile::Npm(json) => {
+                let roots = dependencies
+                    .iter()
+                    .map(|(name, _)| (package_dir.to_string(), name.clone()))
+                    .collect();
+                closure(roots, |(from, name)| npm_package(json, from, name))
+            }
+            Lockfile::Pnpm(yaml) => {
+                let importer = if package_dir.is_empty() {
+                    "."
+                } else {
+                    package_dir
+                };
+                let importer = &yaml["importers"][importer];
+                let mut lines = closure(pnpm_dependencies(importer), |(name, version)| {
+                    pnpm_package(yaml, name, version)
+                });
+                lines.insert(serde_json::to_string(importer).unwrap_or_default());
+                lines
+            }
+            Lockfile::Yarn(entries) => {
+                let roots = dependencies
+                    .iter()
+                    .map(|(name, spec)| {
+                        // Berry prefixes ranges from the registry with "npm:".
+                        let descriptor = format!("{name}@{spec}");
+                        let npm_descriptor = format!("{name}@npm:{spec}");
+                        if !entries.contains_key(&descriptor)
+                            && entries.contains_key(&npm_descriptor)
+                        {
+                            npm_descriptor
+                        } else {
+                            descriptor
+                        }
+                    })
+                    .collect();
+                closure(roots, |descriptor| match entries.get(descriptor) {
+                    // Workspace packages are hashed on their own.
+                    Some(entry) if descriptor.contains("@workspace:") => {
+                        (format!("{descriptor} {}", entry.version), vec![])
+                    }
+                    Some(entry) => (
+                        format!("{descriptor} {}", entry.version),
+                        entry.dependencies.clone(),
+                    ),
+                    None => (format!("{descriptor} unresolved"), vec![]),
+                })
+            }
+            Lockfile::Other(hash) => BTreeSet::from([hash.clone()]),
+            Lockfile::None => BTreeSet::new(),
+        };
+        lines.into_iter().collect::<Vec<_>>().join("\n")
+    }
+}
+
+/// A package in a `yarn.lock`.
+#[derive(Debug, Clone)]
+pub struct YarnEntry {
+    /// The version its descriptors resolved to.
+    version: String,
+    /// Descriptors of its own dependencies, like `js-tokens@^4.0.0`.
+    dependencies: Vec<String>,
+}
+
+/// Follow dependencies from `roots`, returning one line per package reached.
+/// `resolve` returns the line describing a dependency, and its own
+/// dependencies.
+fn closure<K: Clone + Eq + Hash>(
+    roots: Vec<K>,
+    resolve: impl Fn(&K) -> (String, Vec<K>),
+) -> BTreeSet<String> {
+    let mut lines = BTreeSet::new();
+    let mut seen = HashSet::new();
+    let mut queue = roots;
+    while let Some(dependency) = queue.pop() {
+        if !seen.insert(dependency.clone()) {
+            continue;
+        }
+        let (line, dependencies) = resolve(&dependency);
+        lines.insert(line);
+        queue.extend(dependencies);
+    }
+    lines
+}
+
+/// Find the entry a dependency of the package at `from` resolves to in a
+/// `package-lock.json`, returning its line and its own dependencies. Node looks
+/// in the `node_modules` of the package's directory first, then in each
+/// directory above it.
+fn npm_package(
+    json: &serde_json::Value,
+    from: &str,
+    name: &str,
+) -> (String, Vec<(String, String)>) {
+    let mut dir = from;
+    loop {
+        let key = if dir.is_empty() {
+            format!("node_modules/{name}")
+        } else {
+            format!("{dir}/node_modules/{name}")
+        };
+        let entry = &json["packages"][&key];
+        if entry["link"].as_bool() == Some(true) {
+            // Workspace packages are hashed on their own.
+            let target = entry["resolved"].as_str().unwrap_or_default();
+            return (format!("{name}@link:{target}"), vec![]);
+        }
+        if !entry.is_null() {
+            let line = format!(
+                "{name}@{} {}",
+                entry["version"]
+                    .as_str()
+                    .or(entry["resolved"].as_str())
+                    .unwrap_or_default(),
+                entry["integrity"].as_str().unwrap_or_default()
+            );
+            let dependencies = ["dependencies", "optionalDependencies"]
+                .into_iter()
+                .flat_map(|field| entry[field].as_object().into_iter().flatten())
+                .map(|(dependency, _)| (key.clone(), dependency.clone()))
+                .collect();
+            return (line, dependencies);
+        }
+        match dir.rsplit_once('/') {
+            Some((parent, _)) => dir = parent,
+            None if !dir.is_empty() => dir = "",
+            None => return (format!("{name}@unresolved"), vec![]),
+        }
+    }
+}
+
+/// Return the names and resolved versions of an importer's or package's
+/// dependencies in a `pnpm-lock.yaml`.
+fn pnpm_dependencies(node: &serde_yaml::Value) -> Vec<(String, String)> {
+    ["dependencies", "devDependencies", "optionalDependencies"]
+        .into_iter()
+        .flat_map(|field| node[field].as_mapping().into_iter().flatten())
+        .filter_map(|(name, version)| {
+            // Importers list `{ specifier, version }` since lockfile v6,
+            // packages only list the version.
+            let version = version["version"].as_str().or(version.as_str())?;
+            Some((name.as_str()?.to_string(), version.to_string()))
+        })
+        .collect()
+}
+
+/// Find a package in a `pnpm-lock.yaml`, returning its line and its own
+/// dependencies. Packages are keyed `name@version` since lockfile v9, with
+/// their dependencies under `snapshots`, `/name@version` in v6 and
+/// `/name/version` before that.
+fn pnpm_package(
+    yaml: &serde_yaml::Value,
+    name: &str,
+    version: &str,
+) -> (String, Vec<(String, String)>) {
+    let line = format!("{name}@{version}");
+    // Workspace packages are hashed on their own.
+    if version.starts_with("link:") {
+        return (line, vec![]);
+    }
+    let keys = [
+        format!("{name}@{version}"),
+        format!("/{name}@{version}"),
+        format!("/{name}/{version}"),
+    ];
+    let package = keys
+        .iter()
+        .flat_map(|key| {
+            [
+                &yaml["snapshots"][key.as_str()],
+                &yaml["packages"][key.as_str()],
+            ]
+        })
+        .find(|package| !package.is_null());
+    match package {
+        Some(package) => (line, pnpm_dependencies(package)),
+        None => (line, vec![]),
+    }
+}
+
+/// Parse the packages out of a `yarn.lock`, by descriptor. v1 uses a custom
+/// format and Berry uses YAML, but both put comma separated descriptors on
+/// unindented lines, with the version and a `dependencies` section indented
+/// below them.
+fn parse_yarn_lock(content: &str) -> HashMap<String, YarnEntry> {
+    let mut blocks: Vec<(Vec<String>, YarnEntry)> = Vec::new();
+    let mut section = "";
+    for line in content.lines() {
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        if !line.starts_with(' ') {
+            let descriptors = line
+                .trim_end_matches(':')
+                .split(", ")
+                .map(|descriptor| descriptor.trim_matches('"').to_string())
+                .collect();
+            let entry = YarnEntry {
+                version: String::new(),
+                dependencies: Vec::new(),
+            };
+            blocks.push((descriptors, entry));
+            continue;
+        }
+        let Some((_, entry)) = blocks.last_mut() else {
+            continue;
+        };
+        let field = line.trim();
+        if !line.starts_with("    ") {
+            section = field.trim_end_matches(':');
+            if let Some(version) = field.strip_prefix("version") {
+                entry.version = version
+                    .trim_start_matches(':')
+                    .trim()
+                    .trim_matches('"')
+                    .to_string();
+            }
+        } else if section == "dependencies" || section == "optionalDependencies" {
+            // `name "range"` in v1, `name: "npm:range"` in Berry.
+            if let Some((name, range)) = field.split_once(": ").or_else(|| field.split_once(' ')) {
+                let (name, range) = (name.trim_matches('"'), range.trim_matches('"'));
+                entry.dependencies.push(format!("{name}@{range}"));
+            }
+        }
+    }
+    blocks
+        .into_iter()
+        .flat_map(|(descriptors, entry)| {
+            descriptors
+                .into_iter()
+                .map(move |descriptor| (descriptor, entry.clone()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_yarn_lock, Lockfile};
+
+    #[test]
+    fn test_npm_lock() {
+        let json = serde_json::json!({
+            "packages": {
+                "node_modules/react": {
+                    "version": "18.2.0",
+                    "integrity": "sha512-react",
+                    "dependencies": { "loose-envify": "^1.1.0" }
+                },
+                "node_modules/loose-envify": {
+                    "version": "1.4.0",
+                    "integrity": "sha512-loose-envify",
+                    "dependencies": { "js-tokens": "^3.0.0 || ^4.0.0" }
+                },
+                "node_modules/js-tokens": { "version": "4.0.0", "integrity": "sha512-js-tokens-4" },
+                "packages/a/node_modules/js-tokens": { "version": "3.0.2", "integrity": "sha512-js-tokens-3" },
+                "node_modules/b": { "resolved": "packages/b", "link": true },
+            }
+        });
+        let lockfile = Lockfile::Npm(json);
+        let dependencies = [
+            ("react".to_string(), "^18.2.0".to_string()),
+            ("js-tokens".to_string(), "^3.0.0".to_string()),
+            ("b".to_string(), "*".to_string()),
+            ("vite".to_string(), "^5.0.0".to_string()),
+        ];
+
+        assert_eq!(
+            lockfile.package_slice("packages/a", &dependencies),
+            [
+                "b@link:packages/b",
+                "js-tokens@3.0.2 sha512-js-tokens-3",
+                "js-tokens@4.0.0 sha512-js-tokens-4",
+                "loose-envify@1.4.0 sha512-loose-envify",
+                "react@18.2.0 sha512-react",
+                "vite@unresolved",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_pnpm_lock() {
+        let yaml = r#"
+lockfileVersion: '9.0'
+importers:
+  packages/a:
+    dependencies:
+      react-dom:
+        specifier: ^18.2.0
+        version: 18.2.0(react@18.2.0)
+      b:
+        specifier: workspace:*
+        version: link:../b
+snapshots:
+  react-dom@18.2.0(react@18.2.0):
+    dependencies:
+      react: 18.2.0
+      scheduler: 0.23.0
+  react@18.2.0: {}
+  scheduler@0.23.0: {}
+"#;
+        let lockfile = Lockfile::Pnpm(serde_yaml::from_str(yaml).unwrap());
+        let slice = lockfile.package_slice("packages/a", &[]);
+
+        assert!(slice.contains("react-dom@18.2.0(react@18.2.0)\n"));
+        assert!(slice.contains("react@18.2.0\n"));
+        assert!(slice.contains("scheduler@0.23.0"));
+        assert!(slice.contains("b@link:../b"));
+    }
+
+    #[test]
+    fn test_yarn_lock() {
+        let v1 = r#"
+# THIS IS AN AUTOGENERATED FILE. DO NOT EDIT THIS FILE DIRECTLY.
+# yarn lockfile v1
+
+
+"js-tokens@^3.0.0 || ^4.0.0", js-tokens@^4.0.0:
+  version "4.0.0"
+  resolved "https://registry.yarnpkg.com/js-tokens/-/js-tokens-4.0.0.tgz"
+
+loose-envify@^1.1.0:
+  version "1.4.0"
+  dependencies:
+    js-tokens "^3.0.0 || ^4.0.0"
+"#;
+        let berry = r#"
+__metadata:
+  version: 8
+
+"react@npm:^18.0.0, react@npm:^18.2.0":
+  version: 18.2.0
+  resolution: "react@npm:18.2.0"
+  dependencies:
+    loose-envify: "npm:^1.1.0"
+
+"loose-envify@npm:^1.1.0":
+  version: 1.4.0
+  dependencies:
+    js-tokens: "npm:^4.0.0"
+
+"js-tokens@npm:^4.0.0":
+  version: 4.0.0
+"#;
+        let lockfile = Lockfile::Yarn(parse_yarn_lock(&format!("{v1}{berry}")));
+        let dependencies = [
+            ("loose-envify".to_string(), "^1.1.0".to_string()),
+            ("react".to_string(), "^18.2.0".to_string()),
+            ("vite".to_string(), "^5.0.0".to_string()),
+        ];
+
+        assert_eq!(
+            lockfile.package_slice("packages/a", &dependencies),
+            [
+                "js-tokens@^3.0.0 || ^4.0.0 4.0.0",
+                "js-tokens@npm:^4.0.0 4.0.0",
+                "loose-envify@^1.1.0 1.4.0",
+                "loose-envify@npm:^1.1.0 1.4.0",
+                "react@npm:^18.2.0 18.2.0",
+                "vite@^5.0.0 unresolved",
+            ]
+            .join("\n")
+        );
+    }
+}
@@ -1,22 +1,35 @@
 use std::env;
+use std::net::TcpListener;
+use std::path::Path;
 use std::process;
 use std::process::exit;
 
-use colors::{BLUE, BOLD, CYAN, DIM, GREEN, RESET, YELLOW};
+use colors::{BLUE, BOLD, CYAN, DIM, GREEN, RED, RESET, YELLOW};
 use ctx::Ctx;
 
+mod archive;
+mod cache;
 mod colors;
 mod commands;
 mod ctx;
 mod globby;
 mod graph;
 mod hash;
+mod lock;
+mod lockfile;
+mod memo;
 mod monorepo;
+mod npm_range;
+mod remote;
+mod scheduler;
+mod server;
+#[cfg(test)]
+mod test_utils;
+mod validate;
 
 const VERSION: &str = "2.0.0-alpha1";
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let is_debug = is_debug();
     let args: Vec<String> = env::args().collect();
     let separator = args.iter().position(|arg| arg == "--");
 
@@ -24,8 +37,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         Some(index) => (&args[1..index], &args[index + 1..]),
         None => (&args[1..], &[]),
     };
-    let buildc_args: Vec<&str> = buildc_args_slice.iter().map(|str| str.as_str()).collect();
+    let mut buildc_args: Vec<&str> = buildc_args_slice.iter().map(|str| str.as_str()).collect();
     let cmd_args: Vec<&str> = cmd_args_slice.iter().map(|str| str.as_str()).collect();
+    // Debug logs would mix into the JSON printed with --json.
+    let is_debug = is_debug() && !buildc_args.contains(&"--json");
     if is_debug {
         println!("{DIM}[buildc] → Buildc args:  {buildc_args:?}{RESET}");
         println!("{DIM}[buildc] → Command args: {cmd_args:?}{RESET}");
@@ -38,9 +53,22 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         return print_help();
     }
 
+    let concurrency = match take_option(&mut buildc_args, "--concurrency") {
+        None => scheduler::default_concurrency(),
+        Some(value) => value
+            .parse::<usize>()
+            .ok()
+            .filter(|n| *n > 0)
+            .unwrap_or_else(|| {
+                println!("{RED}{BOLD}[buildc] ✘{RESET} --concurrency must be a positive number, got {value:?}");
+                exit(1)
+            }),
+    };
+
     let ctx = Ctx {
         is_debug,
         cmd_args: cmd_args.clone(),
+        concurrency,
     };
 
     match (buildc_args.len(), cmd_args.len()) {
@@ -49,7 +77,34 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         _ => match buildc_args[0] {
             "deps" => commands::deps(&ctx),
             "all" => commands::all(&ctx),
+            "run" => match buildc_args.get(1) {
+                Some(script) => commands::run(&ctx, script),
+                None => print_missing_script(),
+            },
             "graph" => commands::graph(&ctx),
+            "explain" => commands::explain(&ctx, buildc_args.get(1).copied()),
+            "cache-server" => {
+                let mut args = buildc_args[1..].to_vec();
+                let host = take_option(&mut args, "--host").unwrap_or("127.0.0.1");
+                let port = take_option(&mut args, "--port").unwrap_or("8080");
+                let [dir] = args[..] else {
+                    print_invalid_dir(&args)
+                };
+                let token = env::var(remote::TOKEN_ENV)
+                    .ok()
+                    .filter(|token| !token.is_empty());
+                TcpListener::bind(format!("{host}:{port}"))
+                    .and_then(|listener| {
+                        server::serve(&ctx, listener, Path::new(dir), token.as_deref())
+                    })
+                    .map_err(|e| e.into())
+            }
+            "hash" => {
+                let mut package_names = buildc_args[1..].to_vec();
+                let show_inputs = take_flag(&mut package_names, "--inputs");
+                let json = take_flag(&mut package_names, "--json");
+                commands::hash(&ctx, &package_names, show_inputs, json)
+            }
             "clean" | "clear" => commands::clean(&ctx),
             _ => print_unknown_command(),
         },
@@ -70,17 +125,28 @@ fn print_help() -> Result<(), Box<dyn std::error::Error>> {
     println!("  {BOLD}{BLUE  }     {RESET}    {DIM}-- unbuild{RESET}       Build dependencies and run the command, caching the result");
     println!("  {BOLD}{BLUE  }deps {RESET}    {DIM}&& vitest {RESET}       Ensure dependencies are build before running the command");
     println!("  {BOLD}{BLUE  }all  {RESET}    {DIM}          {RESET}       Build all packages in the monorepo, caching the results");
+    println!("  {BOLD}{BLUE  }run  {RESET}    {DIM}<script>  {RESET}       Run a package.json script in all packages, in dependency order, caching the results");
     println!();
     println!("  {BOLD}{GREEN }graph{RESET}    {DIM}          {RESET}       Print the dependency graph");
+    println!("  {BOLD}{GREEN }explain{RESET}  {DIM}[package] {RESET}       Show which inputs changed since a package was last cached");
+    println!("  {BOLD}{GREEN }hash {RESET}    {DIM}[pkg...]  {RESET}       Print package hashes {DIM}(--inputs to list hashed files, --json){RESET}");
     println!();
     println!("  {BOLD}{YELLOW}clean{RESET}    {DIM}          {RESET}       Delete build cache {DIM}(buildc clear){RESET}");
     println!();
+    println!("  {BOLD}{CYAN  }cache-server{RESET} {DIM}<dir>{RESET}        Serve a directory as a remote cache {DIM}(--port 8080, --host 127.0.0.1){RESET}");
+    println!();
+    println!("{BOLD}Options:{RESET}");
+    println!("  {BOLD}--concurrency N{RESET}                  Max number of packages to build at once {DIM}(default: number of CPUs){RESET}");
+    println!();
     println!("{BOLD}Examples:{RESET}");
     println!();
     println!("  buildc -- unbuild              {DIM}Run unbuild after building dependencies{RESET}");
     println!("  buildc -- tsup --minify        {DIM}Run TSup with CLI flags{RESET}");
     println!("  buildc deps && jest            {DIM}Run tests after after dependencies are built{RESET}");
     println!("  buildc deps && tsc --noEmit    {DIM}Run type checks after dependencies are built{RESET}");
+    println!("  buildc all --concurrency 2     {DIM}Build everything, at most 2 packages at a time{RESET}");
+    println!("  buildc run lint                {DIM}Run the lint script in every package that has one{RESET}");
+    println!("  buildc hash --json             {DIM}Print package hashes as JSON, to key CI caches{RESET}");
     println!();
     println!("Learn more about Buildc:    {CYAN}https://github.com/aklinker1/buildc{RESET}");
     Ok(())
@@ -91,11 +157,49 @@ fn print_version() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+fn print_missing_script() -> ! {
+    println!("Missing script name. Usage: {CYAN}buildc run <script>{RESET}");
+    exit(1)
+}
+
+fn print_invalid_dir(args: &[&str]) -> ! {
+    if args.is_empty() {
+        println!("Missing directory to serve. Usage: {CYAN}buildc cache-server <dir>{RESET}");
+    } else {
+        println!("Expected a single directory to serve, got {args:?}. Usage: {CYAN}buildc cache-server <dir>{RESET}");
+    }
+    exit(1)
+}
+
 fn print_unknown_command() -> ! {
     println!("Unknown command. Run {CYAN}buildc --help{RESET} for more details.");
     exit(1)
 }
 
+/// Remove an option and its value (`--name value` or `--name=value`) from the
+/// args, returning the value if the option was passed.
+fn take_option<'a>(args: &mut Vec<&'a str>, name: &str) -> Option<&'a str> {
+    let index = args
+        .iter()
+        .position(|arg| *arg == name || arg.starts_with(&format!("{name}=")))?;
+    let arg = args.remove(index);
+    match arg.split_once('=') {
+        Some((_, value)) => Some(value),
+        None if index < args.len() => Some(args.remove(index)),
+        None => {
+            println!("{RED}{BOLD}[buildc] ✘{RESET} Missing value for {name}");
+            exit(1)
+        }
+    }
+}
+
+/// Remove a flag from the args, returning whether it was passed.
+fn take_flag(args: &mut Vec<&str>, name: &str) -> bool {
+    let len = args.len();
+    args.retain(|arg| *arg != name);
+    args.len() != len
+}
+
 fn array_includes_either(arr: &Vec<&str>, a: &str, b: &str) -> bool {
     arr.iter().any(|item| *item == a || *item == b)
 }
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::cache::CACHE_VERSION;
+
+/// Name of a package's memo file, inside its cache directory.
+pub const MEMO_FILE: &str = "hashes.json";
+
+/// Files modified this recently before they were hashed aren't memoized. A
+/// file could be written again within the same timestamp tick without its
+/// mtime changing, and some file systems only store mtimes to the second, or
+/// two seconds for FAT.
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+/// The stat data used to decide whether a file changed since it was hashed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileStat {
+    size: u64,
+    /// Nanoseconds since the Unix epoch.
+    mtime: u128,
+    inode: u64,
+}
+
+impl FileStat {
+    pub fn new(metadata: &fs::Metadata) -> FileStat {
+        FileStat {
+            size: metadata.len(),
+            mtime: metadata
+                .modified()
+                .ok()
+                .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+                .unwrap_or_default()
+                .as_nanos(),
+            inode: inode(metadata),
+        }
+    }
+
+    /// Whether the file was modified too close to `hashed_at` to trust its
+    /// mtime next time.
+    fn is_racy(&self, hashed_at: SystemTime) -> bool {
+        let hashed_at = hashed_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        self.mtime + RACY_WINDOW.as_nanos() > hashed_at
+    }
+}
+
+#[cfg(unix)]
+fn inode(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &fs::Metadata) -> u64 {
+    0
+}
+
+/// A package's file hashes from its last run, by path relative to the package
+/// directory. Files whose stat data hasn't changed reuse their old hash
+/// instead of being read again.
+#[derive(Debug, Default, PartialEq)]
+pub struct HashMemo {
+    entries: HashMap<String, (FileStat, String)>,
+}
+
+impl HashMemo {
+    /// Read a memo file. Missing, invalid or outdated memos are empty.
+    pub fn load(path: &Path) -> HashMemo {
+        let Some(json) = fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<serde_json::Value>(&content).ok())
+        else {
+            return HashMemo::default();
+        };
+        if json["version"].as_u64() != Some(CACHE_VERSION.into()) {
+            return HashMemo::default();
+        }
+
+        let entries = json["files"]
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter_map(|(path, entry)| {
+                let stat = FileStat {
+                    size: entry["size"].as_u64()?,
+                    // Nanoseconds don't fit in a JSON number, store them as a string.
+                    mtime: entry["mtime"].as_str()?.parse().ok()?,
+                    inode: entry["inode"].as_u64()?,
+                };
+                Some((path.clone(), (stat, entry["hash"].as_str()?.to_string())))
+            })
+            .collect();
+        HashMemo { entries }
+    }
+
+    /// Merge the memo into the memo file. The same package can be hashed by
+    /// several threads or buildc processes at once, without holding its lock,
+    /// so entries already in the file are kept unless this memo replaces them
+    /// or their file no longer exists in `dir`.
+    ///
+    /// The file is written to a temporary file renamed into place, so a
+    /// process reading it never sees a partial file.
+    pub fn save(&self, path: &Path, dir: &Path) -> io::Result<()> {
+        static SAVES: AtomicUsize = AtomicUsize::new(0);
+
+        let mut entries = HashMemo::load(path).entries;
+        entries.retain(|file, _| !self.entries.contains_key(file) && dir.join(file).is_file());
+        entries.extend(self.entries.clone());
+        let files = entries
+            .iter()
+            .map(|(path, (stat, hash))| {
+                let entry = serde_json::json!({
+                    "size": stat.size,
+                    "mtime": stat.mtime.to_string(),
+                    "inode": stat.inode,
+                    "hash": hash,
+                });
+                (path.clone(), entry)
+            })
+            .collect::<serde_json::Map<_, _>>();
+        let json = serde_json::json!({ "version": CACHE_VERSION, "files": files });
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(format!(
+            ".tmp-{}-{}",
+            std::process::id(),
+            SAVES.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&temp, serde_json::to_string(&json)?)?;
+        fs::rename(&temp, path)
+    }
+
+    /// Return the memoized hash of a file, if its stat data is unchanged.
+    pub fn get(&self, path: &str, stat: &FileStat) -> Option<&str> {
+        self.entries
+            .get(path)
+            .filter(|(memo_stat, _)| memo_stat == stat)
+            .map(|(_, hash)| hash.as_str())
+    }
+
+    /// Remember a file's hash, unless it was modified too close to
+    /// `hashed_at`, the time just before it was read.
+    pub fn insert(&mut self, path: String, stat: FileStat, hash: String, hashed_at: SystemTime) {
+        if !stat.is_racy(hashed_at) {
+            self.entries.insert(path, (stat, hash));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::time::{Duration, SystemTime};
+
+    use super::{FileStat, HashMemo};
+    use crate::test_utils::test_dir;
+
+    #[test]
+    fn test_save_merges_memo_file() {
+        let dir = test_dir("memo-merge");
+        let memo_path = dir.join("hashes.json");
+        let mtime = SystemTime::now() - Duration::from_secs(60);
+        let stat = |file: &str| {
+            fs::write(dir.join(file), file).unwrap();
+            let file = File::options().write(true).open(dir.join(file)).unwrap();
+            file.set_modified(mtime).unwrap();
+            FileStat::new(&file.metadata().unwrap())
+        };
+        let (a, b, c) = (stat("a.ts"), stat("b.ts"), stat("c.ts"));
+
+        // Two writers that each hashed some of the files
+        let mut first = HashMemo::default();
+        first.insert(
+            "a.ts".to_string(),
+            a.clone(),
+            "1".to_string(),
+            SystemTime::now(),
+        );
+        first.insert(
+            "c.ts".to_string(),
+            c.clone(),
+            "3".to_string(),
+            SystemTime::now(),
+        );
+        first.save(&memo_path, &dir).unwrap();
+        fs::remove_file(dir.join("c.ts")).unwrap();
+        let mut second = HashMemo::default();
+        second.insert(
+            "b.ts".to_string(),
+            b.clone(),
+            "2".to_string(),
+            SystemTime::now(),
+        );
+        second.save(&memo_path, &dir).unwrap();
+        let memo = HashMemo::load(&memo_path);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(memo.get("a.ts", &a), Some("1"));
+        assert_eq!(memo.get("b.ts", &b), Some("2"));
+        assert_eq!(memo.get("c.ts", &c), None);
+    }
+}
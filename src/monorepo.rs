@@ -1,14 +1,29 @@
+use std::collections::HashMap;
 use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
+use std::process::exit;
+use std::sync::OnceLock;
 
+use crate::cache::{self, LocalCache};
+use crate::colors::{BOLD, RED, RESET};
 use crate::globby::globby;
 use crate::graph::Graph;
-use crate::graph::{Package, PackageConfig};
+use crate::graph::{BundledDependencies, DependencyKind, Package, PackageConfig};
+use crate::lockfile::Lockfile;
+use crate::npm_range;
+use crate::remote::{self, Remote, RemoteCache};
+use crate::validate::{self, ConfigError, PACKAGE_DEFAULT_KEYS};
 
+#[derive(Debug, PartialEq)]
 pub enum PackageManager {
     Pnpm,
     Bun,
+    Npm,
+    /// Yarn v1
+    Yarn,
+    /// Yarn v2+
+    YarnBerry,
 }
 
 impl PackageManager {
@@ -16,14 +31,158 @@ impl PackageManager {
         match self {
             Self::Pnpm => vec!["pnpm", "--silent", "run"],
             Self::Bun => vec!["bun", "--silent", "run"],
+            Self::Npm => vec!["npm", "--silent", "run"],
+            Self::Yarn => vec!["yarn", "--silent", "run"],
+            // Yarn Berry doesn't have a --silent flag, and doesn't print the
+            // extra output v1 does.
+            Self::YarnBerry => vec!["yarn", "run"],
         }
     }
+
+    /// Parse the `packageManager` field of a package.json, like `"yarn@3.6.0"`.
+    fn from_package_manager_field(value: &str) -> Option<PackageManager> {
+        let (name, version) = value.split_once('@').unwrap_or((value, ""));
+        match name {
+            "pnpm" => Some(Self::Pnpm),
+            "bun" => Some(Self::Bun),
+            "npm" => Some(Self::Npm),
+            "yarn" => match version
+                .split('.')
+                .next()
+                .and_then(|v| v.parse::<u32>().ok())
+            {
+                Some(0 | 1) => Some(Self::Yarn),
+                Some(_) => Some(Self::YarnBerry),
+                None => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Detect the package manager from the lockfile in the monorepo root.
+    fn from_lockfile(root: &Path) -> Option<PackageManager> {
+        if root.join("yarn.lock").exists() {
+            return Some(if root.join(".yarnrc.yml").exists() {
+                Self::YarnBerry
+            } else {
+                Self::Yarn
+            });
+        }
+        if root.join("package-lock.json").exists() {
+            return Some(Self::Npm);
+        }
+        if root.join("bun.lockb").exists() || root.join("bun.lock").exists() {
+            return Some(Self::Bun);
+        }
+        if root.join(".yarnrc.yml").exists() {
+            return Some(Self::YarnBerry);
+        }
+        None
+    }
 }
 
 pub struct Monorepo {
     pub root: PathBuf,
     pub package_manager: PackageManager,
     pub package_globs: Vec<String>,
+    pub config: RootConfig,
+    /// Read the first time it's needed.
+    lockfile: OnceLock<Lockfile>,
+    /// Created the first time it's needed.
+    remote_cache: OnceLock<Option<Remote>>,
+    /// Read or created the first time it's needed.
+    inputs_key: OnceLock<[u8; 32]>,
+}
+
+const DEFAULT_ALLOW_CYCLES: bool = false;
+
+/// Repo-wide config, read from `buildc.json` or the `buildc` key of the root
+/// package.json.
+#[derive(Debug, Clone)]
+pub struct RootConfig {
+    /// Whether dependency cycles between packages are allowed. When false,
+    /// commands that build packages fail if the graph has a cycle.
+    pub allow_cycles: bool,
+    /// Upstream tasks of each task, by task name. `^build` refers to the
+    /// `build` task of a package's dependencies, and `build` to the package's
+    /// own `build` task.
+    pub pipeline: HashMap<String, Vec<String>>,
+    /// Repo-wide defaults for each package's `buildc` block, like `outDir`.
+    pub package_defaults: serde_json::Map<String, serde_json::Value>,
+    /// Globs of files, relative to the root, that are part of every package's
+    /// hash, like a shared `tsconfig.base.json`.
+    pub global_inputs: Vec<String>,
+    /// Environment variables that are part of every package's hash, on top
+    /// of the ones listed in each package's config.
+    pub env: Vec<String>,
+    /// Where cache entries are shared with other machines, if anywhere.
+    pub remote_cache: Option<RemoteCache>,
+}
+
+impl RootConfig {
+    /// Return the upstream tasks of a task. Tasks missing from the pipeline
+    /// depend on the same task in the package's dependencies.
+    pub fn task_dependencies(&self, task: &str) -> Vec<String> {
+        self.pipeline
+            .get(task)
+            .cloned()
+            .unwrap_or_else(|| vec![format!("^{task}")])
+    }
+}
+
+impl From<serde_json::Value> for RootConfig {
+    fn from(value: serde_json::Value) -> Self {
+        RootConfig {
+            allow_cycles: value
+                .get("allowCycles")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(DEFAULT_ALLOW_CYCLES),
+            pipeline: value
+                .get("pipeline")
+                .and_then(|v| v.as_object())
+                .map(|pipeline| {
+                    pipeline
+                        .iter()
+                        .map(|(task, config)| {
+                            let depends_on = config
+                                .get("dependsOn")
+                                .and_then(|v| v.as_array())
+                                .map(|arr| {
+                                    arr.iter()
+                                        .filter_map(|v| v.as_str().map(String::from))
+                                        .collect()
+                                })
+                                .unwrap_or_else(|| vec![format!("^{task}")]);
+                            (task.clone(), depends_on)
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            global_inputs: value
+                .get("globalInputs")
+                .and_then(|v| v.as_array())
+                .map(|arr| {
+                    arr.iter()
+                        .filter_map(|v| v.as_str().map(String::from))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            env: value
+                .get("env")
+                .and_then(|v| v.as_array())
+                .map(|arr| {
+                    arr.iter()
+                        .filter_map(|v| v.as_str().map(String::from))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            remote_cache: value.get("remoteCache").and_then(RemoteCache::from_config),
+            package_defaults: PACKAGE_DEFAULT_KEYS
+                .iter()
+                .filter_map(|&key| Some((key.to_string(), value.get(key)?.clone())))
+                .collect(),
+        }
+    }
 }
 
 impl Monorepo {
@@ -31,18 +190,86 @@ impl Monorepo {
         self.root.join(".cache")
     }
 
+    pub fn local_cache(&self) -> LocalCache {
+        LocalCache::new(self.cache_dir())
+    }
+
+    pub fn lockfile(&self) -> &Lockfile {
+        self.lockfile.get_or_init(|| Lockfile::read(&self.root))
+    }
+
+    /// Return the key environment variable values are hashed with in saved
+    /// inputs, see `cache::inputs_key`. When the key can't be saved, a new one
+    /// is used for this run only.
+    pub fn inputs_key(&self) -> &[u8; 32] {
+        self.inputs_key.get_or_init(|| {
+            cache::inputs_key(&self.cache_dir()).unwrap_or_else(|_| {
+                let mut key = [0; 32];
+                let _ = getrandom::getrandom(&mut key);
+                key
+            })
+        })
+    }
+
+    /// Return the remote cache entries are shared through, if one is set up.
+    pub fn remote_cache(&self) -> Option<&Remote> {
+        self.remote_cache
+            .get_or_init(|| remote::remote(&self.root, self.config.remote_cache.as_ref()))
+            .as_ref()
+    }
+
     pub fn to_graph(&self) -> Graph {
-        let package_json_globs = self
+        // Workspace entries starting with "!" exclude packages instead of
+        // including them.
+        let (exclude_globs, include_globs): (Vec<_>, Vec<_>) = self
             .package_globs
             .iter()
-            .map(|glob| format!("{glob}/package.json"))
+            .partition(|glob| glob.starts_with('!'));
+        let package_json_globs = include_globs
+            .iter()
+            .map(|glob| format!("{}/package.json", glob.trim_end_matches('/')))
+            .collect::<Vec<_>>();
+        let mut exclude_package_json_globs = vec!["**/node_modules/**".to_string()];
+        for glob in exclude_globs {
+            let glob = glob.trim_start_matches('!').trim_end_matches('/');
+            exclude_package_json_globs.push(format!("{glob}/package.json"));
+            exclude_package_json_globs.push(format!("{glob}/**"));
+        }
+
+        let matches = globby(
+            &self.root,
+            package_json_globs,
+            exclude_package_json_globs,
+            false,
+        );
+        let package_jsons = matches
+            .into_iter()
+            .map(|package_json| {
+                read_package_json(package_json, &self.config.package_defaults)
+                    .expect("Could not read package.json")
+            })
+            .collect::<Vec<_>>();
+
+        let packages = package_jsons
+            .iter()
+            .map(|package_json| resolve_dependencies(package_json, &package_jsons))
             .collect::<Vec<_>>();
 
-        let mut packages: Vec<Package> = vec![];
-        let matches = globby(&self.root, package_json_globs, vec![]);
-        for package_json in matches {
-            let package = read_package_json(package_json).expect("Could not read package.json");
-            packages.push(package);
+        // Report every invalid config at once instead of silently falling
+        // back to defaults, which could restore stale output from the cache.
+        let mut has_config_errors = false;
+        for (package_json, package) in package_jsons.iter().zip(&packages) {
+            let bundled_errors = bundled_dependency_errors(package);
+            for error in package_json.config_errors.iter().chain(&bundled_errors) {
+                has_config_errors = true;
+                println!(
+                    "{RED}{BOLD}[buildc] ✘{RESET} {}: Invalid config, {error}",
+                    package.name
+                );
+            }
+        }
+        if has_config_errors {
+            exit(1);
         }
 
         Graph::new(packages)
@@ -55,9 +282,13 @@ pub fn find() -> Option<Monorepo> {
     loop {
         if let Some((package_manager, package_globs)) = read_workspace(&current_dir) {
             return Some(Monorepo {
+                config: read_root_config(&current_dir),
                 root: current_dir.to_owned(),
                 package_globs,
                 package_manager,
+                lockfile: OnceLock::new(),
+                remote_cache: OnceLock::new(),
+                inputs_key: OnceLock::new(),
             });
         }
 
@@ -68,7 +299,36 @@ pub fn find() -> Option<Monorepo> {
     None
 }
 
-fn read_workspace(path: &PathBuf) -> Option<(PackageManager, Vec<String>)> {
+/// Read the root config from `buildc.json`, falling back to the `buildc` key
+/// of the root package.json.
+fn read_root_config(root: &Path) -> RootConfig {
+    let (file, path, json) = match fs::read_to_string(root.join("buildc.json")) {
+        Ok(content) => {
+            let json = serde_json::from_str::<serde_json::Value>(&content)
+                .expect("buildc.json is not valid JSON");
+            ("buildc.json", "", json)
+        }
+        Err(_) => {
+            let json = fs::read_to_string(root.join("package.json"))
+                .ok()
+                .and_then(|content| serde_json::from_str::<serde_json::Value>(&content).ok())
+                .map(|json| json["buildc"].to_owned())
+                .unwrap_or_default();
+            ("package.json", "buildc", json)
+        }
+    };
+
+    let errors = validate::root_config(&json, path);
+    for error in &errors {
+        println!("{RED}{BOLD}[buildc] ✘{RESET} {file}: Invalid config, {error}");
+    }
+    if !errors.is_empty() {
+        exit(1);
+    }
+    RootConfig::from(json)
+}
+
+fn read_workspace(path: &Path) -> Option<(PackageManager, Vec<String>)> {
     if path.join("pnpm-workspace.yaml").exists() {
         let content = fs::read_to_string(path.join("pnpm-workspace.yaml"))
             .expect("Failed to read pnpm-workspace.yaml");
@@ -91,11 +351,19 @@ fn read_workspace(path: &PathBuf) -> Option<(PackageManager, Vec<String>)> {
                 // Ignore package.json files without workspaces
                 return None;
             }
+            let package_manager = json["packageManager"]
+                .as_str()
+                .and_then(PackageManager::from_package_manager_field)
+                .or_else(|| PackageManager::from_lockfile(path))
+                .unwrap_or(PackageManager::Bun);
+            // Yarn v1 also supports `"workspaces": { "packages": [...] }`
+            let workspaces = json["workspaces"]
+                .as_array()
+                .or_else(|| json["workspaces"]["packages"].as_array())
+                .expect("package.json#workspaces must be an array");
             return Some((
-                PackageManager::Bun,
-                json["workspaces"]
-                    .as_array()
-                    .unwrap()
+                package_manager,
+                workspaces
                     .iter()
                     .filter_map(|v| v.as_str())
                     .map(String::from)
@@ -107,7 +375,22 @@ fn read_workspace(path: &PathBuf) -> Option<(PackageManager, Vec<String>)> {
     None
 }
 
-fn read_package_json(package_json_path: PathBuf) -> std::io::Result<Package> {
+/// A package read from its package.json, before its dependencies have been
+/// resolved to other packages in the workspace.
+struct PackageJson {
+    package: Package,
+    version: Option<String>,
+    /// Dependency names, their version specifier and the section they're
+    /// listed in, like `("b", "^1.0.0", DependencyKind::Dependencies)`.
+    dependency_specs: Vec<(String, String, DependencyKind)>,
+    /// Problems found in the package's `buildc` block.
+    config_errors: Vec<ConfigError>,
+}
+
+fn read_package_json(
+    package_json_path: PathBuf,
+    config_defaults: &serde_json::Map<String, serde_json::Value>,
+) -> std::io::Result<PackageJson> {
     let content = fs::read_to_string(&package_json_path)?;
     let json: serde_json::Value = serde_json::from_str(&content)?;
 
@@ -116,40 +399,269 @@ fn read_package_json(package_json_path: PathBuf) -> std::io::Result<Package> {
         .expect("package.json must have a valid \"name\"")
         .to_string();
 
-    let build_script = json["scripts"]["build"]
-        .as_str()
-        .map(|script| script.to_string());
-
-    let mut dependency_names = Vec::new();
-    if let Some(deps) = json["dependencies"].as_object() {
-        dependency_names.extend(
-            deps.iter()
-                .filter(|(_, version)| {
-                    version
+    let scripts = json["scripts"]
+        .as_object()
+        .map(|scripts| {
+            scripts
+                .iter()
+                .filter_map(|(name, script)| {
+                    script
                         .as_str()
-                        .map_or(false, |s| s.starts_with("workspace:"))
+                        .map(|script| (name.clone(), script.to_string()))
                 })
-                .map(|(name, _)| name.clone()),
-        );
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut dependency_specs = Vec::new();
+    for kind in DependencyKind::ALL {
+        if let Some(deps) = json[kind.field()].as_object() {
+            dependency_specs.extend(deps.iter().filter_map(|(name, spec)| {
+                spec.as_str()
+                    .map(|spec| (name.clone(), spec.to_string(), kind))
+            }));
+        }
+    }
+
+    Ok(PackageJson {
+        package: Package {
+            dir: package_json_path.parent().unwrap().into(),
+            name,
+            scripts,
+            dependency_names: Vec::new(),
+            dependency_kinds: HashMap::new(),
+            config: PackageConfig::with_defaults(config_defaults, json["buildc"].to_owned()),
+        },
+        version: json["version"].as_str().map(String::from),
+        dependency_specs,
+        config_errors: validate::package_config(&json["buildc"], "buildc"),
+    })
+}
+
+/// Return the package with its dependencies on other workspace packages, only
+/// following the kinds of dependency listed in its `dependencyKinds`.
+fn resolve_dependencies(package_json: &PackageJson, workspace_packages: &[PackageJson]) -> Package {
+    let mut package = package_json.package.clone();
+    for (name, spec, kind) in &package_json.dependency_specs {
+        if !package.config.dependency_kinds.contains(kind) {
+            continue;
+        }
+        if let Some(dependency) = resolve_dependency(&package.dir, name, spec, workspace_packages) {
+            if !package.dependency_names.contains(&dependency) {
+                package.dependency_names.push(dependency.clone());
+            }
+            let kinds = package.dependency_kinds.entry(dependency).or_default();
+            if !kinds.contains(kind) {
+                kinds.push(*kind);
+            }
+        }
+    }
+    package
+}
+
+/// Check that the dependencies listed in a package's `bundlesDependencies` are
+/// workspace packages it depends on, to catch typos that would leave a bundled
+/// dependency out of the hash.
+fn bundled_dependency_errors(package: &Package) -> Vec<ConfigError> {
+    let BundledDependencies::Only(names) = &package.config.bundles_dependencies else {
+        return Vec::new();
+    };
+    names
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| !package.dependency_names.contains(name))
+        .map(|(i, name)| ConfigError {
+            path: format!("buildc.bundlesDependencies[{i}]"),
+            message: format!("expected a workspace package this package depends on, got {name:?}"),
+        })
+        .collect()
+}
+
+/// Return the name of the workspace package a dependency resolves to, if any.
+///
+/// - `workspace:` always links to the workspace package with the same name.
+/// - `link:` and `file:` link to the workspace package at that path.
+/// - Other specifiers link to the workspace package with the same name when
+///   its version satisfies the range, like npm and Yarn v1 do.
+fn resolve_dependency(
+    package_dir: &Path,
+    name: &str,
+    spec: &str,
+    workspace_packages: &[PackageJson],
+) -> Option<String> {
+    if spec.starts_with("workspace:") {
+        return workspace_packages
+            .iter()
+            .find(|local| local.package.name == name)
+            .map(|local| local.package.name.clone());
+    }
+
+    if let Some(path) = spec
+        .strip_prefix("link:")
+        .or_else(|| spec.strip_prefix("file:"))
+    {
+        let target = normalize_path(&package_dir.join(path));
+        return workspace_packages
+            .iter()
+            .find(|local| normalize_path(&local.package.dir) == target)
+            .map(|local| local.package.name.clone());
+    }
+
+    workspace_packages
+        .iter()
+        .find(|local| local.package.name == name)
+        .filter(|local| {
+            local
+                .version
+                .as_ref()
+                .is_some_and(|version| npm_range::satisfies(version, spec))
+        })
+        .map(|local| local.package.name.clone())
+}
+
+/// Resolve `.` and `..` components without touching the file system.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
     }
-    if let Some(dev_deps) = json["devDependencies"].as_object() {
-        dependency_names.extend(
-            dev_deps
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+
+    use super::{
+        bundled_dependency_errors, resolve_dependencies, resolve_dependency, PackageJson,
+        PackageManager,
+    };
+    use crate::graph::{DependencyKind, Graph, Package, PackageConfig};
+
+    fn package_json(dir: &str, name: &str, version: &str) -> PackageJson {
+        PackageJson {
+            package: Package {
+                dir: PathBuf::from(dir),
+                name: name.to_string(),
+                scripts: HashMap::new(),
+                dependency_names: vec![],
+                dependency_kinds: HashMap::new(),
+                config: PackageConfig::from(serde_json::Value::Null),
+            },
+            version: Some(version.to_string()),
+            dependency_specs: vec![],
+            config_errors: vec![],
+        }
+    }
+
+    #[test]
+    fn test_resolve_dependency() {
+        let locals = vec![
+            package_json("/repo/packages/b", "b", "1.2.0"),
+            package_json("/repo/packages/c", "c", "2.0.0"),
+        ];
+        let dir = Path::new("/repo/packages/a");
+        let resolve = |name, spec| resolve_dependency(dir, name, spec, &locals);
+
+        assert_eq!(resolve("b", "^1.0.0"), Some("b".to_string()));
+        assert_eq!(resolve("b", "^2.0.0"), None);
+        assert_eq!(resolve("c", "workspace:^1.0.0"), Some("c".to_string()));
+        assert_eq!(resolve("c-alias", "link:../c"), Some("c".to_string()));
+        assert_eq!(resolve("b", "file:./b"), None);
+        assert_eq!(resolve("react", "^18.0.0"), None);
+    }
+
+    #[test]
+    fn test_dependency_kinds() {
+        let mut ui = package_json("/repo/packages/ui", "ui", "1.0.0");
+        ui.dependency_specs = vec![
+            (
+                "utils".to_string(),
+                "^1.0.0".to_string(),
+                DependencyKind::Dependencies,
+            ),
+            (
+                "react".to_string(),
+                "^1.0.0".to_string(),
+                DependencyKind::PeerDependencies,
+            ),
+        ];
+        let locals = vec![
+            ui,
+            package_json("/repo/packages/utils", "utils", "1.0.0"),
+            package_json("/repo/packages/react", "react", "1.0.0"),
+        ];
+        let build_order = |locals: &[PackageJson]| {
+            let packages = locals
                 .iter()
-                .filter(|(_, version)| {
-                    version
-                        .as_str()
-                        .map_or(false, |s| s.starts_with("workspace:"))
-                })
-                .map(|(name, _)| name.clone()),
+                .map(|local| resolve_dependencies(local, locals))
+                .collect();
+            Graph::new(packages)
+                .get_package_dependencies_build_order("ui")
+                .unwrap()
+                .into_iter()
+                .map(|package| package.name)
+                .collect::<Vec<_>>()
+        };
+
+        let ui = resolve_dependencies(&locals[0], &locals);
+        assert_eq!(
+            ui.dependency_kinds["react"],
+            vec![DependencyKind::PeerDependencies]
         );
+        assert_eq!(build_order(&locals), vec!["utils", "react"]);
+
+        let mut locals = locals;
+        locals[0].package.config = PackageConfig::from(serde_json::json!({
+            "dependencyKinds": ["dependencies", "devDependencies"]
+        }));
+        let ui = resolve_dependencies(&locals[0], &locals);
+        assert!(!ui.dependency_kinds.contains_key("react"));
+        assert_eq!(build_order(&locals), vec!["utils"]);
     }
 
-    Ok(Package {
-        dir: package_json_path.parent().unwrap().into(),
-        name,
-        build_script,
-        dependency_names,
-        config: PackageConfig::from(json["buildc"].to_owned()),
-    })
+    #[test]
+    fn test_bundled_dependency_errors() {
+        let mut app = package_json("/repo/packages/app", "app", "1.0.0");
+        app.dependency_specs = vec![(
+            "ui".to_string(),
+            "^1.0.0".to_string(),
+            DependencyKind::Dependencies,
+        )];
+        app.package.config = PackageConfig::from(serde_json::json!({
+            "bundlesDependencies": ["ui", "uii"]
+        }));
+        let locals = vec![app, package_json("/repo/packages/ui", "ui", "1.0.0")];
+
+        let errors = bundled_dependency_errors(&resolve_dependencies(&locals[0], &locals))
+            .iter()
+            .map(|error| error.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec!["buildc.bundlesDependencies[1]: expected a workspace package this package depends on, got \"uii\""]
+        );
+    }
+
+    #[test]
+    fn test_package_manager_field() {
+        let parse = PackageManager::from_package_manager_field;
+        assert_eq!(parse("npm@10.2.0"), Some(PackageManager::Npm));
+        assert_eq!(parse("pnpm@9.0.0"), Some(PackageManager::Pnpm));
+        assert_eq!(parse("bun@1.1.27"), Some(PackageManager::Bun));
+        assert_eq!(parse("yarn@1.22.19"), Some(PackageManager::Yarn));
+        assert_eq!(
+            parse("yarn@4.1.0+sha256.abc"),
+            Some(PackageManager::YarnBerry)
+        );
+        assert_eq!(parse("yarn"), None);
+        assert_eq!(parse("deno@2.0.0"), None);
+    }
 }
@@ -0,0 +1,87 @@
+use semver::{Version, VersionReq};
+
+/// Check if a version satisfies an npm version range, like `^1.2.0`,
+/// `>=1 <2 || 3.x` or `1.0.0 - 1.4.0`. Invalid versions and ranges never match.
+pub fn satisfies(version: &str, range: &str) -> bool {
+    let Ok(version) = Version::parse(version.trim().trim_start_matches('v')) else {
+        return false;
+    };
+    range
+        .split("||")
+        .filter_map(to_version_req)
+        .any(|req| req.matches(&version))
+}
+
+/// Convert a single npm comparator set (no `||`) to a Cargo-style requirement.
+fn to_version_req(set: &str) -> Option<VersionReq> {
+    let set = set.trim();
+    let comparators = match set.split_once(" - ") {
+        Some((from, to)) => vec![format!(">={}", from.trim()), format!("<={}", to.trim())],
+        None => split_comparators(set),
+    };
+
+    let comparators = comparators
+        .iter()
+        .map(|comparator| to_cargo_comparator(comparator))
+        .filter(|comparator| comparator != "*")
+        .collect::<Vec<_>>();
+    if comparators.is_empty() {
+        return Some(VersionReq::STAR);
+    }
+    VersionReq::parse(&comparators.join(", ")).ok()
+}
+
+/// Split a comparator set on whitespace, keeping operators attached to their
+/// version (`>= 1.2` becomes `>=1.2`).
+fn split_comparators(set: &str) -> Vec<String> {
+    let mut comparators: Vec<String> = Vec::new();
+    for part in set.split_whitespace() {
+        match comparators.last_mut() {
+            Some(last) if last.chars().all(|c| "<>=^~".contains(c)) => last.push_str(part),
+            _ => comparators.push(part.to_string()),
+        }
+    }
+    comparators
+}
+
+/// npm treats a bare version as an exact match, while Cargo treats it as a
+/// caret requirement, so add an explicit `=`.
+fn to_cargo_comparator(comparator: &str) -> String {
+    let operator_len = comparator
+        .find(|c: char| !"<>=^~".contains(c))
+        .unwrap_or(comparator.len());
+    let (operator, version) = comparator.split_at(operator_len);
+    let version = version.trim_start_matches('v');
+    if version.is_empty() || version == "x" || version == "X" || version == "*" {
+        return "*".to_string();
+    }
+    match operator {
+        "" => format!("={version}"),
+        "~>" => format!("~{version}"),
+        _ => format!("{operator}{version}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::satisfies;
+
+    #[test]
+    fn test_satisfies() {
+        assert!(satisfies("1.2.3", "^1.2.0"));
+        assert!(!satisfies("2.0.0", "^1.2.0"));
+        assert!(satisfies("1.2.9", "~1.2.3"));
+        assert!(!satisfies("1.3.0", "~1.2.3"));
+        assert!(satisfies("1.2.3", "1.2.3"));
+        assert!(!satisfies("1.2.4", "1.2.3"));
+        assert!(satisfies("1.5.0", "1.x"));
+        assert!(satisfies("3.1.0", ">=1 <2 || 3.x"));
+        assert!(satisfies("1.5.0", ">= 1.0.0 < 2.0.0"));
+        assert!(satisfies("1.4.0", "1.0.0 - 1.4.0"));
+        assert!(!satisfies("1.4.1", "1.0.0 - 1.4.0"));
+        assert!(satisfies("0.0.1", "*"));
+        assert!(satisfies("0.0.1", ""));
+        assert!(!satisfies("1.0.0", "latest"));
+        assert!(!satisfies("not-a-version", "*"));
+    }
+}
@@ -0,0 +1,255 @@
+use std::env;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::cache::LocalCache;
+
+/// Environment variable overriding the remote cache's URL from the config.
+pub const URL_ENV: &str = "BUILDC_REMOTE_CACHE_URL";
+/// Environment variable holding the bearer token sent to the remote cache, and
+/// required by `buildc cache-server`.
+pub const TOKEN_ENV: &str = "BUILDC_REMOTE_CACHE_TOKEN";
+
+/// Storage for cache entries, like the local `.cache` directory, see
+/// `cache::LocalCache`, or a remote cache shared between machines, like CI jobs
+/// and teammates. Entries are exchanged as single artifacts, keyed by
+/// `<package>/<script>/<hash>`, the same path the entry has in `.cache`. See
+/// `cache::upload` and `cache::download`.
+pub trait CacheBackend: Send + Sync {
+    /// Where the artifacts are stored, for logs.
+    fn location(&self) -> String;
+    /// Whether an artifact exists.
+    fn has(&self, key: &str) -> io::Result<bool>;
+    /// Download an artifact to `dest`, returning false when it doesn't exist.
+    fn get(&self, key: &str, dest: &Path) -> io::Result<bool>;
+    /// Upload an artifact, replacing any existing one.
+    fn put(&self, key: &str, artifact: &Path) -> io::Result<()>;
+}
+
+/// Return the key of a package script's cache entry, see `CacheBackend`.
+pub fn entry_key(package: &str, script: &str, hash: &str) -> String {
+    format!("{package}/{script}/{hash}")
+}
+
+/// Where the remote cache is, from the root config's `remoteCache`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RemoteCache {
+    /// A server speaking the HTTP protocol served by `buildc cache-server`.
+    Url(String),
+    /// A directory, like a shared network drive, with the same layout as
+    /// `.cache`. Relative to the workspace root.
+    Dir(PathBuf),
+}
+
+impl RemoteCache {
+    pub fn from_config(value: &serde_json::Value) -> Option<RemoteCache> {
+        if let Some(url) = value.get("url").and_then(|v| v.as_str()) {
+            return Some(RemoteCache::Url(url.to_string()));
+        }
+        let dir = value.get("dir").and_then(|v| v.as_str())?;
+        Some(RemoteCache::Dir(PathBuf::from(dir)))
+    }
+}
+
+/// Create the configured remote cache. The URL can also be set, or
+/// overridden, with `BUILDC_REMOTE_CACHE_URL`.
+pub fn remote(root: &Path, config: Option<&RemoteCache>) -> Option<Remote> {
+    let config = match env::var(URL_ENV) {
+        Ok(url) if !url.is_empty() => Some(RemoteCache::Url(url)),
+        _ => config.cloned(),
+    };
+    let backend: Box<dyn CacheBackend> = match config? {
+        RemoteCache::Url(url) => Box::new(HttpBackend::new(
+            &url,
+            env::var(TOKEN_ENV).ok().filter(|token| !token.is_empty()),
+        )),
+        RemoteCache::Dir(dir) => Box::new(LocalCache::new(root.join(dir))),
+    };
+    Some(Remote::new(backend))
+}
+
+/// The remote cache used during a run. After its first error, like the server
+/// being unreachable, it's skipped for the rest of the run so each package
+/// doesn't wait on the same timeouts. Only that first error is returned.
+pub struct Remote {
+    backend: Box<dyn CacheBackend>,
+    disabled: AtomicBool,
+}
+
+impl Remote {
+    pub fn new(backend: Box<dyn CacheBackend>) -> Remote {
+        Remote {
+            backend,
+            disabled: AtomicBool::new(false),
+        }
+    }
+
+    /// Call the backend unless it's disabled, returning `skipped` when it is.
+    fn call<T>(
+        &self,
+        skipped: T,
+        f: impl FnOnce(&dyn CacheBackend) -> io::Result<T>,
+    ) -> io::Result<T> {
+        if self.disabled.load(Ordering::Relaxed) {
+            return Ok(skipped);
+        }
+        f(self.backend.as_ref()).or_else(|err| {
+            if self.disabled.swap(true, Ordering::Relaxed) {
+                return Ok(skipped);
+            }
+            Err(io::Error::new(
+                err.kind(),
+                format!("{err}, skipping it for the rest of the run"),
+            ))
+        })
+    }
+}
+
+impl CacheBackend for Remote {
+    fn location(&self) -> String {
+        self.backend.location()
+    }
+
+    fn has(&self, key: &str) -> io::Result<bool> {
+        self.call(false, |backend| backend.has(key))
+    }
+
+    fn get(&self, key: &str, dest: &Path) -> io::Result<bool> {
+        self.call(false, |backend| backend.get(key, dest))
+    }
+
+    fn put(&self, key: &str, artifact: &Path) -> io::Result<()> {
+        self.call((), |backend| backend.put(key, artifact))
+    }
+}
+
+/// Artifacts stored on a server, read and written with `GET`, `HEAD` and `PUT`
+/// requests to `<url>/<key>`. When a token is set, it's sent as a
+/// bearer token.
+pub struct HttpBackend {
+    url: String,
+    token: Option<String>,
+    agent: ureq::Agent,
+}
+
+impl HttpBackend {
+    pub fn new(url: &str, token: Option<String>) -> HttpBackend {
+        HttpBackend {
+            url: url.trim_end_matches('/').to_string(),
+            token,
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(Duration::from_secs(5))
+                .timeout_read(Duration::from_secs(60))
+                .build(),
+        }
+    }
+
+    fn request(&self, method: &str, key: &str) -> ureq::Request {
+        let request = self.agent.request(method, &format!("{}/{key}", self.url));
+        match &self.token {
+            Some(token) => request.set("Authorization", &format!("Bearer {token}")),
+            None => request,
+        }
+    }
+}
+
+impl CacheBackend for HttpBackend {
+    fn location(&self) -> String {
+        self.url.clone()
+    }
+
+    fn has(&self, key: &str) -> io::Result<bool> {
+        match self.request("HEAD", key).call() {
+            Ok(_) => Ok(true),
+            Err(ureq::Error::Status(404, _)) => Ok(false),
+            Err(err) => Err(http_error(err)),
+        }
+    }
+
+    fn get(&self, key: &str, dest: &Path) -> io::Result<bool> {
+        match self.request("GET", key).call() {
+            Ok(response) => {
+                io::copy(&mut response.into_reader(), &mut File::create(dest)?)?;
+                Ok(true)
+            }
+            Err(ureq::Error::Status(404, _)) => Ok(false),
+            Err(err) => Err(http_error(err)),
+        }
+    }
+
+    fn put(&self, key: &str, artifact: &Path) -> io::Result<()> {
+        let file = File::open(artifact)?;
+        let len = file.metadata()?.len();
+        self.request("PUT", key)
+            .set("Content-Length", &len.to_string())
+            .send(file)
+            .map(|_| ())
+            .map_err(http_error)
+    }
+}
+
+fn http_error(err: ureq::Error) -> io::Error {
+    io::Error::other(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::{CacheBackend, Remote};
+
+    /// A backend that can't be reached, counting how often it's called.
+    struct Unreachable(Arc<AtomicUsize>);
+
+    impl Unreachable {
+        fn fail<T>(&self) -> io::Result<T> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "connection refused",
+            ))
+        }
+    }
+
+    impl CacheBackend for Unreachable {
+        fn location(&self) -> String {
+            "unreachable".to_string()
+        }
+
+        fn has(&self, _key: &str) -> io::Result<bool> {
+            self.fail()
+        }
+
+        fn get(&self, _key: &str, _dest: &Path) -> io::Result<bool> {
+            self.fail()
+        }
+
+        fn put(&self, _key: &str, _artifact: &Path) -> io::Result<()> {
+            self.fail()
+        }
+    }
+
+    #[test]
+    fn test_remote_is_disabled_after_an_error() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let remote = Remote::new(Box::new(Unreachable(calls.clone())));
+
+        let first = remote.get("a/build/0f3e", Path::new("artifact"));
+        let second = remote.get("b/build/0f3e", Path::new("artifact"));
+        let put = remote.put("a/build/0f3e", Path::new("artifact"));
+
+        assert_eq!(
+            first.unwrap_err().to_string(),
+            "connection refused, skipping it for the rest of the run"
+        );
+        assert!(!second.unwrap());
+        assert!(put.is_ok());
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+}
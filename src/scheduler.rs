@@ -0,0 +1,225 @@
+use std::collections::{HashMap, HashSet};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::thread;
+
+/// A unit of work for the scheduler, identified by `id`. A job is started once
+/// every job listed in `dependencies` has finished. Dependencies that are not
+/// part of the scheduled jobs are treated as already finished.
+pub struct Job<T> {
+    pub id: String,
+    pub dependencies: Vec<String>,
+    pub value: T,
+}
+
+/// Number of jobs to run at the same time when `--concurrency` isn't passed.
+pub fn default_concurrency() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+/// Run jobs in parallel, with at most `concurrency` jobs in flight at once.
+/// Jobs are started in the order they're passed in as soon as their
+/// dependencies are done, so pass them in build order.
+///
+/// When a job fails, no new jobs are started. Jobs that are already running
+/// are waited on, then the first error is returned. Panics inside a job are
+/// handled the same way, then resumed once everything has stopped.
+pub fn run<T, E, F>(jobs: Vec<Job<T>>, concurrency: usize, run_job: F) -> Result<(), E>
+where
+    T: Sync,
+    E: Send,
+    F: Fn(&T) -> Result<(), E> + Sync,
+{
+    let concurrency = concurrency.max(1);
+    let ids = jobs
+        .iter()
+        .map(|job| job.id.as_str())
+        .collect::<HashSet<_>>();
+
+    // Number of unfinished dependencies for each job, and the reverse edges
+    // used to update those counts when a job finishes.
+    let mut remaining = jobs
+        .iter()
+        .map(|job| {
+            let count = job
+                .dependencies
+                .iter()
+                .filter(|dep| ids.contains(dep.as_str()) && **dep != job.id)
+                .collect::<HashSet<_>>()
+                .len();
+            (job.id.as_str(), count)
+        })
+        .collect::<HashMap<_, _>>();
+    let mut dependents: HashMap<&str, Vec<&str>> = HashMap::new();
+    for job in &jobs {
+        for dep in job.dependencies.iter().collect::<HashSet<_>>() {
+            if ids.contains(dep.as_str()) && *dep != job.id {
+                dependents
+                    .entry(dep.as_str())
+                    .or_default()
+                    .push(job.id.as_str());
+            }
+        }
+    }
+
+    let mut pending = jobs.iter().collect::<Vec<_>>();
+    let mut error = None;
+    let mut panic_payload = None;
+
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel::<(&str, thread::Result<Result<(), E>>)>();
+        let mut running = 0;
+
+        loop {
+            if error.is_none() && panic_payload.is_none() {
+                while running < concurrency {
+                    let next = pending
+                        .iter()
+                        .position(|job| remaining[job.id.as_str()] == 0)
+                        // If nothing is ready and nothing is running, the
+                        // remaining jobs depend on each other. Start the first
+                        // one in build order rather than waiting forever.
+                        .or(if running == 0 && !pending.is_empty() {
+                            Some(0)
+                        } else {
+                            None
+                        });
+                    let Some(index) = next else {
+                        break;
+                    };
+
+                    let job = pending.remove(index);
+                    let sender = sender.clone();
+                    let run_job = &run_job;
+                    running += 1;
+                    scope.spawn(move || {
+                        let result = panic::catch_unwind(AssertUnwindSafe(|| run_job(&job.value)));
+                        let _ = sender.send((job.id.as_str(), result));
+                    });
+                }
+            }
+
+            if running == 0 {
+                break;
+            }
+
+            let (id, result) = receiver.recv().unwrap();
+            running -= 1;
+            match result {
+                Ok(Ok(())) => {
+                    for dependent in dependents.get(id).into_iter().flatten() {
+                        if let Some(count) = remaining.get_mut(dependent) {
+                            *count = count.saturating_sub(1);
+                        }
+                    }
+                }
+                Ok(Err(err)) => {
+                    if error.is_none() {
+                        error = Some(err);
+                    }
+                }
+                Err(payload) => {
+                    if panic_payload.is_none() {
+                        panic_payload = Some(payload);
+                    }
+                }
+            }
+        }
+    });
+
+    if let Some(payload) = panic_payload {
+        panic::resume_unwind(payload);
+    }
+
+    match error {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Mutex;
+
+    use super::{run, Job};
+
+    fn job(id: &str, dependencies: Vec<&str>) -> Job<String> {
+        Job {
+            id: id.to_string(),
+            dependencies: dependencies.iter().map(|s| s.to_string()).collect(),
+            value: id.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_dependencies_finish_first() {
+        let jobs = vec![
+            job("c", vec![]),
+            job("d", vec![]),
+            job("b", vec!["c"]),
+            job("a", vec!["b", "c", "d"]),
+        ];
+        let finished = Mutex::new(Vec::new());
+        run::<_, (), _>(jobs, 4, |id| {
+            finished.lock().unwrap().push(id.clone());
+            Ok(())
+        })
+        .unwrap();
+
+        let finished = finished.into_inner().unwrap();
+        let position = |id: &str| finished.iter().position(|f| f == id).unwrap();
+        assert_eq!(finished.len(), 4);
+        assert!(position("c") < position("b"));
+        assert!(position("b") < position("a"));
+        assert!(position("d") < position("a"));
+    }
+
+    #[test]
+    fn test_failure_stops_dependents() {
+        let jobs = vec![job("c", vec![]), job("b", vec!["c"]), job("a", vec!["b"])];
+        let finished = Mutex::new(Vec::new());
+        let result = run(jobs, 2, |id| {
+            if id == "b" {
+                return Err(3);
+            }
+            finished.lock().unwrap().push(id.clone());
+            Ok(())
+        });
+
+        assert_eq!(result, Err(3));
+        assert_eq!(finished.into_inner().unwrap(), vec!["c"]);
+    }
+
+    #[test]
+    fn test_panics_stop_dependents() {
+        let jobs = vec![job("c", vec![]), job("b", vec!["c"]), job("a", vec!["b"])];
+        let finished = Mutex::new(Vec::new());
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            run::<_, (), _>(jobs, 2, |id| {
+                if id == "b" {
+                    panic!("b failed");
+                }
+                finished.lock().unwrap().push(id.clone());
+                Ok(())
+            })
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(finished.into_inner().unwrap(), vec!["c"]);
+    }
+
+    #[test]
+    fn test_cycles_do_not_deadlock() {
+        let jobs = vec![job("a", vec!["b"]), job("b", vec!["a"])];
+        let count = Mutex::new(0);
+        run::<_, (), _>(jobs, 2, |_| {
+            *count.lock().unwrap() += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(count.into_inner().unwrap(), 2);
+    }
+}
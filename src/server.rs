@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use crate::cache::LocalCache;
+use crate::colors::{BOLD, DIM, GREEN, RED, RESET};
+use crate::ctx::Ctx;
+use crate::remote::{CacheBackend, TOKEN_ENV};
+
+/// How long a connection can wait on the client before it's dropped.
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// Number of requests handled at the same time. Other connections wait to be
+/// accepted.
+const WORKERS: usize = 16;
+
+/// Serve a directory of cache entries, laid out like `.cache`, over the remote
+/// cache protocol: `GET`, `HEAD` and `PUT` requests to
+/// `/<package>/<script>/<hash>`. With a token, usually from
+/// `BUILDC_REMOTE_CACHE_TOKEN`, every request must send it as a bearer token.
+///
+/// This is a minimal stand-in for a real remote cache, to try out and test the
+/// setup locally. Each connection handles a single request, on one of
+/// `WORKERS` threads.
+pub fn serve(ctx: &Ctx, listener: TcpListener, dir: &Path, token: Option<&str>) -> io::Result<()> {
+    let cache = LocalCache::new(dir.to_path_buf());
+    println!(
+        "{GREEN}[buildc] ✓{RESET} Serving {dir:?} at http://{}{}",
+        listener.local_addr()?,
+        if token.is_some() {
+            format!(" {DIM}(requires {TOKEN_ENV}){RESET}")
+        } else {
+            String::new()
+        }
+    );
+
+    thread::scope(|scope| {
+        for _ in 0..WORKERS {
+            scope.spawn(|| {
+                for stream in listener.incoming() {
+                    let result = stream
+                        .and_then(|stream| {
+                            stream.set_read_timeout(Some(TIMEOUT))?;
+                            stream.set_write_timeout(Some(TIMEOUT))?;
+                            Ok(stream)
+                        })
+                        .and_then(|stream| handle(ctx, stream, &cache, token));
+                    if let Err(e) = result {
+                        println!("{RED}{BOLD}[buildc] ✘{RESET} Error handling request: {e}");
+                    }
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn handle(
+    ctx: &Ctx,
+    mut stream: TcpStream,
+    cache: &LocalCache,
+    token: Option<&str>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or_default();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+    if ctx.is_debug {
+        println!("{DIM}[buildc] → {method} {target}{RESET}");
+    }
+
+    if let Some(token) = token {
+        // BLAKE3 hashes compare in constant time, so response times don't
+        // leak how much of the token a guess got right.
+        let expected = blake3::hash(format!("Bearer {token}").as_bytes());
+        let authorization = headers.get("authorization").map(String::as_str);
+        if blake3::hash(authorization.unwrap_or_default().as_bytes()) != expected {
+            return respond(&mut stream, "401 Unauthorized", 0);
+        }
+    }
+    let Some(key) = parse_key(target) else {
+        return respond(&mut stream, "400 Bad Request", 0);
+    };
+
+    match method {
+        "HEAD" => match cache.has(key)? {
+            true => respond(&mut stream, "200 OK", 0),
+            false => respond(&mut stream, "404 Not Found", 0),
+        },
+        "GET" => {
+            let artifact = cache.temp_artifact(key)?;
+            let result = cache.get(key, &artifact).and_then(|found| {
+                if !found {
+                    return respond(&mut stream, "404 Not Found", 0);
+                }
+                let mut file = File::open(&artifact)?;
+                respond(&mut stream, "200 OK", file.metadata()?.len())?;
+                io::copy(&mut file, &mut stream).map(|_| ())
+            });
+            let _ = fs::remove_file(&artifact);
+            result
+        }
+        "PUT" => {
+            let Some(len) = headers
+                .get("content-length")
+                .and_then(|len| len.parse().ok())
+            else {
+                return respond(&mut stream, "411 Length Required", 0);
+            };
+            let artifact = cache.temp_artifact(key)?;
+            let result =
+                receive(&mut reader, len, &artifact).and_then(|_| cache.put(key, &artifact));
+            let _ = fs::remove_file(&artifact);
+            match result {
+                Ok(()) => respond(&mut stream, "200 OK", 0),
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    respond(&mut stream, "400 Bad Request", 0)
+                }
+                Err(err) => Err(err),
+            }
+        }
+        _ => respond(&mut stream, "405 Method Not Allowed", 0),
+    }
+}
+
+/// Write a request body of `len` bytes to `path`.
+fn receive(reader: impl Read, len: u64, path: &Path) -> io::Result<()> {
+    let written = io::copy(&mut reader.take(len), &mut File::create(path)?)?;
+    if written < len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("expected {len} bytes, got {written}"),
+        ));
+    }
+    Ok(())
+}
+
+/// Write the status line and headers of a response. A non-zero
+/// `content_length` must be followed by the body.
+fn respond(stream: &mut TcpStream, status: &str, content_length: u64) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Length: {content_length}\r\nConnection: close\r\n\r\n"
+    )
+}
+
+/// Return the entry key in a request path, like `/@scope/name/build/<hash>`,
+/// see `CacheBackend`. Paths that could escape the served directory are
+/// rejected.
+fn parse_key(target: &str) -> Option<&str> {
+    let key = target.split('?').next()?.strip_prefix('/')?;
+    let segments = key.split('/').collect::<Vec<_>>();
+    let is_valid_segment = |segment: &&str| {
+        !segment.is_empty() && *segment != "." && *segment != ".." && !segment.contains('\\')
+    };
+    let is_valid_hash =
+        |hash: &&str| !hash.is_empty() && hash.chars().all(|c| c.is_ascii_hexdigit());
+    match segments.as_slice() {
+        [name, script, hash]
+            if is_valid_segment(name) && is_valid_segment(script) && is_valid_hash(hash) =>
+        {
+            Some(key)
+        }
+        [scope, name, script, hash]
+            if scope.starts_with('@')
+                && [scope, name, script].into_iter().all(is_valid_segment)
+                && is_valid_hash(hash) =>
+        {
+            Some(key)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::path::{Path, PathBuf};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{parse_key, serve};
+    use crate::cache::{self, CacheFormat, LocalCache, RestoreStrategy};
+    use crate::remote::{CacheBackend, HttpBackend};
+    use crate::test_utils::{test_dir, CTX};
+
+    const KEY: &str = "a/build/0f3e";
+
+    /// Serve a new directory's `served/` on a free port. Returns the directory
+    /// and the server's address.
+    fn start_server(name: &str, token: Option<&'static str>) -> (PathBuf, String) {
+        let dir = test_dir(&format!("server-{name}"));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let served = dir.join("served");
+        thread::spawn(move || serve(&CTX, listener, &served, token));
+        (dir, address)
+    }
+
+    /// Send a raw request and return the response's status line.
+    fn send(address: &str, request: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    /// Cache a built package's output as `KEY` in `dir/cache`.
+    fn cached_entry(dir: &Path) -> LocalCache {
+        let package_dir = dir.join("package");
+        fs::create_dir_all(package_dir.join("dist")).unwrap();
+        fs::write(package_dir.join("dist/index.js"), "built").unwrap();
+        let local = LocalCache::new(dir.join("cache"));
+        cache::save(
+            &CTX,
+            &package_dir,
+            &["dist".to_string()],
+            RestoreStrategy::Copy,
+            CacheFormat::Directory,
+            Duration::ZERO,
+            &local.entry_dir(KEY),
+        )
+        .unwrap();
+        local
+    }
+
+    #[test]
+    fn test_http_round_trip() {
+        let (dir, address) = start_server("round-trip", None);
+        let local = cached_entry(&dir);
+        let other = LocalCache::new(dir.join("other-cache"));
+        let remote = HttpBackend::new(&format!("http://{address}/"), None);
+
+        let missing = cache::download(&remote, &other, KEY).unwrap();
+        cache::upload(&remote, &local, KEY).unwrap();
+        let has_entry = remote.has(KEY).unwrap();
+        let found = cache::download(&remote, &other, KEY).unwrap();
+        let output = fs::read_to_string(other.entry_dir(KEY).join("outputs/dist/index.js"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(!missing);
+        assert!(has_entry);
+        assert!(found);
+        assert_eq!(output.unwrap(), "built");
+    }
+
+    #[test]
+    fn test_concurrent_puts() {
+        let (dir, address) = start_server("concurrent-puts", None);
+        let local = cached_entry(&dir);
+        let artifact = dir.join("artifact.tar.zst");
+        local.get(KEY, &artifact).unwrap();
+        let remote = HttpBackend::new(&format!("http://{address}"), None);
+
+        let results = thread::scope(|scope| {
+            let puts = (0..8)
+                .map(|_| scope.spawn(|| remote.put(KEY, &artifact)))
+                .collect::<Vec<_>>();
+            puts.into_iter()
+                .map(|put| put.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        let has_entry = LocalCache::new(dir.join("served")).has(KEY).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(results.iter().all(Result::is_ok), "{results:?}");
+        assert!(has_entry);
+    }
+
+    #[test]
+    fn test_http_token() {
+        let (dir, address) = start_server("token", Some("secret"));
+        let url = format!("http://{address}");
+
+        let without_token = HttpBackend::new(&url, None).has(KEY);
+        let wrong_token = HttpBackend::new(&url, Some("wrong".to_string())).has(KEY);
+        let with_token = HttpBackend::new(&url, Some("secret".to_string())).has(KEY);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(without_token.unwrap_err().to_string().contains("401"));
+        assert!(wrong_token.unwrap_err().to_string().contains("401"));
+        assert!(!with_token.unwrap());
+    }
+
+    #[test]
+    fn test_put_content_length() {
+        let (dir, address) = start_server("content-length", None);
+        let served = LocalCache::new(dir.join("served"));
+
+        let missing = send(&address, "PUT /a/build/0f3e HTTP/1.1\r\n\r\nabc");
+        let short = send(
+            &address,
+            "PUT /a/build/0f3e HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc",
+        );
+        let has_entry = served.has(KEY).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(missing, "HTTP/1.1 411 Length Required");
+        assert_eq!(short, "HTTP/1.1 400 Bad Request");
+        assert!(!has_entry);
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(parse_key("/a/build/0f3e"), Some("a/build/0f3e"));
+        assert_eq!(
+            parse_key("/@scope/a/build/0f3e"),
+            Some("@scope/a/build/0f3e")
+        );
+        assert_eq!(parse_key("/a/0f3e"), None);
+        assert_eq!(parse_key("/../build/0f3e"), None);
+        assert_eq!(parse_key("/a/b/build/0f3e"), None);
+        assert_eq!(parse_key("/@scope/../build/0f3e"), None);
+        assert_eq!(parse_key("/a/../../etc"), None);
+        assert_eq!(parse_key("/a/build/"), None);
+    }
+}
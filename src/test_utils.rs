@@ -0,0 +1,21 @@
+use std::fs;
+use std::path::PathBuf;
+
+use crate::ctx::Ctx;
+
+/// Context for tests, without debug logs. Static so threads spawned by a test
+/// can borrow it.
+pub static CTX: Ctx = Ctx {
+    is_debug: false,
+    cmd_args: Vec::new(),
+    concurrency: 1,
+};
+
+/// Create an empty temporary directory for a test. `name` must be unique
+/// across tests, since they run in parallel.
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("buildc-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
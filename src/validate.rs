@@ -0,0 +1,404 @@
+use std::path::{Component, Path};
+
+use serde_json::Value;
+
+use crate::cache::{CacheFormat, RestoreStrategy};
+use crate::graph::DependencyKind;
+
+/// A problem found in a config block, like a misspelled key or a value of the
+/// wrong type.
+#[derive(Debug, PartialEq)]
+pub struct ConfigError {
+    /// JSON path of the bad key, like `buildc.include[2]`.
+    pub path: String,
+    /// What was expected instead.
+    pub message: String,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+/// Keys allowed in a package's `buildc` block.
+const PACKAGE_KEYS: &[&str] = &[
+    "cache",
+    "outDir",
+    "outputs",
+    "include",
+    "exclude",
+    "dependencyKinds",
+    "restoreStrategy",
+    "cacheFormat",
+    "env",
+    "bundlesDependencies",
+];
+/// Keys of the root config that set defaults for every package's config.
+pub const PACKAGE_DEFAULT_KEYS: &[&str] = &[
+    "outDir",
+    "outputs",
+    "include",
+    "exclude",
+    "cache",
+    "restoreStrategy",
+    "cacheFormat",
+];
+/// Keys allowed in the root config, on top of the package defaults.
+const ROOT_KEYS: &[&str] = &[
+    "$schema",
+    "allowCycles",
+    "pipeline",
+    "globalInputs",
+    "env",
+    "remoteCache",
+];
+/// Keys allowed for each task in the root config's `pipeline`.
+const PIPELINE_TASK_KEYS: &[&str] = &["dependsOn"];
+/// Keys allowed in the root config's `remoteCache`, only one can be set.
+const REMOTE_CACHE_KEYS: &[&str] = &["url", "dir"];
+
+/// Validate a package's `buildc` block. `path` is where the block is, like
+/// `buildc`. A missing block is valid.
+pub fn package_config(value: &Value, path: &str) -> Vec<ConfigError> {
+    let mut errors = Vec::new();
+    if value.is_null() {
+        return errors;
+    }
+    let Some(object) = expect_object(value, path, &mut errors) else {
+        return errors;
+    };
+    for (key, value) in object {
+        let key_path = join(path, key);
+        match key.as_str() {
+            "cache" => expect_bool(value, &key_path, &mut errors),
+            "outDir" => match value.as_str() {
+                Some(path) => expect_inside_package(path, &key_path, &mut errors),
+                None => errors.push(wrong_type(&key_path, "a string", value)),
+            },
+            "outputs" => {
+                for (i, path) in expect_string_array(value, &key_path, &mut errors) {
+                    expect_inside_package(path, &format!("{key_path}[{i}]"), &mut errors);
+                }
+            }
+            "include" | "exclude" | "env" => {
+                expect_string_array(value, &key_path, &mut errors);
+            }
+            "dependencyKinds" => {
+                for (i, item) in expect_string_array(value, &key_path, &mut errors) {
+                    if DependencyKind::from_field(item).is_none() {
+                        errors.push(ConfigError {
+                            path: format!("{key_path}[{i}]"),
+                            message: format!(
+                                "expected one of {}, got {item:?}",
+                                format_options(&DependencyKind::ALL.map(|kind| kind.field()))
+                            ),
+                        });
+                    }
+                }
+            }
+            "restoreStrategy" => {
+                let strategies = RestoreStrategy::ALL.map(|strategy| strategy.name());
+                match value.as_str() {
+                    Some(name) if RestoreStrategy::from_name(name).is_some() => {}
+                    _ => errors.push(wrong_type(
+                        &key_path,
+                        &format!("one of {}", format_options(&strategies)),
+                        value,
+                    )),
+                }
+            }
+            "bundlesDependencies" => {
+                if !value.is_boolean() {
+                    if value.is_array() {
+                        expect_string_array(value, &key_path, &mut errors);
+                    } else {
+                        errors.push(wrong_type(
+                            &key_path,
+                            "a boolean or an array of package names",
+                            value,
+                        ));
+                    }
+                }
+            }
+            "cacheFormat" => {
+                let formats = CacheFormat::ALL.map(|format| format.name());
+                match value.as_str() {
+                    Some(name) if CacheFormat::from_name(name).is_some() => {}
+                    _ => errors.push(wrong_type(
+                        &key_path,
+                        &format!("one of {}", format_options(&formats)),
+                        value,
+                    )),
+                }
+            }
+            _ => errors.push(unknown_key(&key_path, key, PACKAGE_KEYS)),
+        }
+    }
+    errors
+}
+
+/// Validate the root config, read from `buildc.json` or the root package.json.
+/// `path` is where the config is, empty for the root of `buildc.json`.
+pub fn root_config(value: &Value, path: &str) -> Vec<ConfigError> {
+    let mut errors = Vec::new();
+    if value.is_null() {
+        return errors;
+    }
+    let Some(object) = expect_object(value, path, &mut errors) else {
+        return errors;
+    };
+
+    // Package defaults share their keys and types with the package config.
+    let (defaults, root): (serde_json::Map<_, _>, serde_json::Map<_, _>) = object
+        .clone()
+        .into_iter()
+        .partition(|(key, _)| PACKAGE_DEFAULT_KEYS.contains(&key.as_str()));
+    errors.extend(package_config(&Value::Object(defaults), path));
+
+    for (key, value) in &root {
+        let key_path = join(path, key);
+        match key.as_str() {
+            "$schema" => expect_string(value, &key_path, &mut errors),
+            "allowCycles" => expect_bool(value, &key_path, &mut errors),
+            "globalInputs" | "env" => {
+                expect_string_array(value, &key_path, &mut errors);
+            }
+            "remoteCache" => {
+                let Some(remote_cache) = expect_object(value, &key_path, &mut errors) else {
+                    continue;
+                };
+                for (key, value) in remote_cache {
+                    let path = join(&key_path, key);
+                    match key.as_str() {
+                        "url" | "dir" => expect_string(value, &path, &mut errors),
+                        _ => errors.push(unknown_key(&path, key, REMOTE_CACHE_KEYS)),
+                    }
+                }
+                let locations = REMOTE_CACHE_KEYS
+                    .iter()
+                    .filter(|key| remote_cache.contains_key(**key))
+                    .count();
+                if locations != 1 {
+                    errors.push(ConfigError {
+                        path: key_path,
+                        message: "expected either a \"url\" or a \"dir\"".to_string(),
+                    });
+                }
+            }
+            "pipeline" => {
+                let Some(pipeline) = expect_object(value, &key_path, &mut errors) else {
+                    continue;
+                };
+                for (task, config) in pipeline {
+                    let task_path = join(&key_path, task);
+                    let Some(config) = expect_object(config, &task_path, &mut errors) else {
+                        continue;
+                    };
+                    for (key, value) in config {
+                        let path = join(&task_path, key);
+                        match key.as_str() {
+                            "dependsOn" => {
+                                expect_string_array(value, &path, &mut errors);
+                            }
+                            _ => errors.push(unknown_key(&path, key, PIPELINE_TASK_KEYS)),
+                        }
+                    }
+                }
+            }
+            _ => {
+                let allowed = [PACKAGE_DEFAULT_KEYS, ROOT_KEYS].concat();
+                errors.push(unknown_key(&key_path, key, &allowed));
+            }
+        }
+    }
+    errors
+}
+
+/// Append a key to a JSON path. An empty path is the document root.
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{path}.{key}")
+    }
+}
+
+fn expect_object<'a>(
+    value: &'a Value,
+    path: &str,
+    errors: &mut Vec<ConfigError>,
+) -> Option<&'a serde_json::Map<String, Value>> {
+    if value.is_object() {
+        return value.as_object();
+    }
+    errors.push(wrong_type(path, "an object", value));
+    None
+}
+
+fn expect_bool(value: &Value, path: &str, errors: &mut Vec<ConfigError>) {
+    if !value.is_boolean() {
+        errors.push(wrong_type(path, "a boolean", value));
+    }
+}
+
+fn expect_string(value: &Value, path: &str, errors: &mut Vec<ConfigError>) {
+    if !value.is_string() {
+        errors.push(wrong_type(path, "a string", value));
+    }
+}
+
+/// Check that a value is a list of strings, returning the valid items and
+/// their index.
+fn expect_string_array<'a>(
+    value: &'a Value,
+    path: &str,
+    errors: &mut Vec<ConfigError>,
+) -> Vec<(usize, &'a str)> {
+    let Some(items) = value.as_array() else {
+        errors.push(wrong_type(path, "an array of strings", value));
+        return Vec::new();
+    };
+    let mut strings = Vec::new();
+    for (i, item) in items.iter().enumerate() {
+        match item.as_str() {
+            Some(item) => strings.push((i, item)),
+            None => errors.push(wrong_type(&format!("{path}[{i}]"), "a string", item)),
+        }
+    }
+    strings
+}
+
+/// Check that an output path or glob stays inside the package directory.
+/// Outputs are deleted and overwritten when restoring from the cache, so they
+/// can't point anywhere else, like `../shared` or `/tmp`.
+fn expect_inside_package(path: &str, key_path: &str, errors: &mut Vec<ConfigError>) {
+    let components = Path::new(path).components().collect::<Vec<_>>();
+    let is_inside = components
+        .iter()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        && components
+            .iter()
+            .any(|component| matches!(component, Component::Normal(_)));
+    if !is_inside {
+        errors.push(ConfigError {
+            path: key_path.to_string(),
+            message: format!("expected a path inside the package, got {path:?}"),
+        });
+    }
+}
+
+fn wrong_type(path: &str, expected: &str, value: &Value) -> ConfigError {
+    let actual = match value {
+        Value::Null => "null".to_string(),
+        Value::Bool(b) => format!("boolean {b}"),
+        Value::Number(n) => format!("number {n}"),
+        Value::String(s) => format!("string {s:?}"),
+        Value::Array(_) => "an array".to_string(),
+        Value::Object(_) => "an object".to_string(),
+    };
+    ConfigError {
+        path: path.to_string(),
+        message: format!("expected {expected}, got {actual}"),
+    }
+}
+
+fn unknown_key(path: &str, key: &str, allowed: &[&str]) -> ConfigError {
+    // Most unknown keys are typos with the wrong casing, like "outdir".
+    let message = match allowed
+        .iter()
+        .find(|allowed| allowed.eq_ignore_ascii_case(key))
+    {
+        Some(suggestion) => format!("unknown key, did you mean {suggestion:?}?"),
+        None => format!("unknown key, expected one of {}", format_options(allowed)),
+    };
+    ConfigError {
+        path: path.to_string(),
+        message,
+    }
+}
+
+fn format_options(options: &[&str]) -> String {
+    options
+        .iter()
+        .map(|option| format!("{option:?}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{package_config, root_config};
+
+    #[test]
+    fn test_package_config() {
+        assert_eq!(package_config(&serde_json::Value::Null, "buildc"), vec![]);
+        assert_eq!(
+            package_config(
+                &json!({ "outDir": "lib", "dependencyKinds": ["dependencies"] }),
+                "buildc"
+            ),
+            vec![]
+        );
+
+        let errors = package_config(
+            &json!({
+                "outdir": "lib",
+                "cache": "false",
+                "include": "src/**",
+                "exclude": ["a", 1],
+                "dependencyKinds": ["deps"],
+                "outputs": ["./dist", "../shared", "/tmp/out", "."],
+            }),
+            "buildc",
+        )
+        .iter()
+        .map(|error| error.to_string())
+        .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                "buildc.cache: expected a boolean, got string \"false\"",
+                "buildc.dependencyKinds[0]: expected one of \"dependencies\", \"devDependencies\", \"peerDependencies\", \"optionalDependencies\", got \"deps\"",
+                "buildc.exclude[1]: expected a string, got number 1",
+                "buildc.include: expected an array of strings, got string \"src/**\"",
+                "buildc.outdir: unknown key, did you mean \"outDir\"?",
+                "buildc.outputs[1]: expected a path inside the package, got \"../shared\"",
+                "buildc.outputs[2]: expected a path inside the package, got \"/tmp/out\"",
+                "buildc.outputs[3]: expected a path inside the package, got \".\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_root_config() {
+        let errors = root_config(
+            &json!({
+                "$schema": "./node_modules/@aklinker1/buildc/schema.json",
+                "outDir": 1,
+                "allowCycles": true,
+                "dependencyKinds": ["dependencies"],
+                "pipeline": { "test": { "dependsOn": "build", "cache": false } },
+            }),
+            "",
+        )
+        .iter()
+        .map(|error| error.to_string())
+        .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                "outDir: expected a string, got number 1",
+                "dependencyKinds: unknown key, expected one of \"outDir\", \"outputs\", \"include\", \"exclude\", \"cache\", \"restoreStrategy\", \"cacheFormat\", \"$schema\", \"allowCycles\", \"pipeline\", \"globalInputs\", \"env\", \"remoteCache\"",
+                "pipeline.test.cache: unknown key, expected one of \"dependsOn\"",
+                "pipeline.test.dependsOn: expected an array of strings, got string \"build\"",
+            ]
+        );
+    }
+}